@@ -1,6 +1,6 @@
 #include "syscalls.h"
 
-void _start() {
+void _start(int argc, char **argv, char **envp) {
     for(int i = 0; i < 15; i++) {
         sys_set_leds(RED | BLUE);
         sys_delay(100);
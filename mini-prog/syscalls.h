@@ -12,6 +12,11 @@ static int ecall(int name, int arg1) {
     return syscall_number;
 }
 
+// Programs start with the arguments given to `run`, argv[0] being the file
+// name and argv[argc] a null pointer, and the shell variables as "NAME=value"
+// strings in envp, ending with a null pointer
+void _start(int argc, char **argv, char **envp);
+
 #define RED 1
 #define GREEN 2
 #define BLUE 4
@@ -29,3 +34,24 @@ static int sys_delay(int milliseconds) {
 static int sys_exit(int exit_code) {
     ecall(4, exit_code);
 }
+
+// Runs the program at address until it exits and returns its exit code;
+// terminated is set to whether it was stopped with Ctrl-C
+static int sys_exec(void *address, int argc, char **argv, char **envp, int *terminated) {
+    register long a0 asm("a0") = 3;
+    register long a1 asm("a1") = (long) address;
+    register long a2 asm("a2") = argc;
+    register long a3 asm("a3") = (long) argv;
+    register long a4 asm("a4") = (long) envp;
+
+    // The program may change any memory, so nothing is kept in registers
+    asm volatile (
+        "ecall"
+        : "+r" (a0), "+r" (a1)
+        : "r" (a2), "r" (a3), "r" (a4)
+        : "memory"
+    );
+
+    *terminated = a1;
+    return a0;
+}
@@ -1,5 +1,10 @@
+#[cfg(not(test))]
 use core::arch::asm;
 
+#[cfg(test)]
+pub(crate) use host::*;
+
+#[cfg(not(test))]
 pub(crate) fn put_char(byte: u8) {
     unsafe {
         asm!(
@@ -13,6 +18,7 @@ pub(crate) fn put_char(byte: u8) {
     };
 }
 
+#[cfg(not(test))]
 pub(crate) fn get_char() -> u8 {
     let byte;
 
@@ -30,6 +36,99 @@ pub(crate) fn get_char() -> u8 {
     byte
 }
 
+/// Stops the running command or program.
+pub(crate) const CTRL_C: u8 = 0x03;
+
+/// The operation was stopped with Ctrl-C.
+pub(crate) struct Terminated;
+
+// USART0 registers, see init_usart in start.s
+const USART_STAT: *const u32 = 0x40013800 as *const u32;
+const USART_DATA: *const u32 = 0x40013804 as *const u32;
+
+// The core runs from the internal 8 MHz oscillator
+pub(crate) const CYCLES_PER_MILLISECOND: u64 = 8_000;
+
+/// Like `get_char`, but returns immediately if no byte was received.
+pub(crate) fn try_get_char() -> Option<u8> {
+    let status = unsafe { USART_STAT.read_volatile() };
+
+    // RBNE (read data buffer not empty)
+    if status & (1 << 5) == 0 {
+        return None;
+    }
+
+    let byte = unsafe { USART_DATA.read_volatile() } as u8;
+
+    // Parity, frame, or noise error
+    if status & 0b111 != 0 {
+        return None;
+    }
+
+    Some(byte)
+}
+
+/// Waits for a byte for at most `milliseconds`.
+pub(crate) fn get_char_timeout(milliseconds: u32) -> Option<u8> {
+    let deadline = cycle_count() + milliseconds as u64 * CYCLES_PER_MILLISECOND;
+
+    loop {
+        if let Some(byte) = try_get_char() {
+            return Some(byte);
+        }
+
+        if cycle_count() >= deadline {
+            return None;
+        }
+    }
+}
+
+pub(crate) fn sleep(milliseconds: u32) {
+    let deadline = cycle_count() + milliseconds as u64 * CYCLES_PER_MILLISECOND;
+
+    while cycle_count() < deadline {}
+}
+
+/// Like `sleep`, but stops early if Ctrl-C is pressed; other keys are dropped.
+pub(crate) fn sleep_unless_terminated(milliseconds: u32) -> Result<(), Terminated> {
+    let deadline = cycle_count() + milliseconds as u64 * CYCLES_PER_MILLISECOND;
+
+    while cycle_count() < deadline {
+        if try_get_char() == Some(CTRL_C) {
+            return Err(Terminated);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(test))]
+pub(crate) fn cycle_count() -> u64 {
+    loop {
+        let high: u32;
+        let low: u32;
+        let high_again: u32;
+
+        unsafe {
+            asm!(
+                "csrr   {0}, mcycleh",
+                "csrr   {1}, mcycle",
+                "csrr   {2}, mcycleh",
+                out(reg) high,
+                out(reg) low,
+                out(reg) high_again,
+                options(nomem, nostack),
+            )
+        };
+
+        // Retry if the low half overflowed in between
+        if high == high_again {
+            return (high as u64) << 32 | low as u64;
+        }
+    }
+}
+
+#[cfg(not(test))]
 pub(crate) fn flash_page_erase(page_number: u8) {
     unsafe {
         asm!(
@@ -44,6 +143,7 @@ pub(crate) fn flash_page_erase(page_number: u8) {
     };
 }
 
+#[cfg(not(test))]
 pub(crate) fn flash_write(source_page: u8, target_page: u8) {
     unsafe {
         asm!(
@@ -61,6 +161,7 @@ pub(crate) fn flash_write(source_page: u8, target_page: u8) {
     };
 }
 
+#[cfg(not(test))]
 pub(crate) fn ecall1(syscall_number: u8, arg1: u32) {
     unsafe {
         asm!(
@@ -71,3 +172,31 @@ pub(crate) fn ecall1(syscall_number: u8, arg1: u32) {
         )
     };
 }
+
+// Tests run on the host, where there is no hardware to touch
+#[cfg(test)]
+mod host {
+    pub(crate) fn put_char(_byte: u8) {
+        unimplemented!("the USART only exists on the board");
+    }
+
+    pub(crate) fn get_char() -> u8 {
+        unimplemented!("the USART only exists on the board");
+    }
+
+    pub(crate) fn cycle_count() -> u64 {
+        unimplemented!("mcycle only exists on the board");
+    }
+
+    pub(crate) fn flash_page_erase(_page_number: u8) {
+        unimplemented!("flash only exists on the board");
+    }
+
+    pub(crate) fn flash_write(_source_page: u8, _target_page: u8) {
+        unimplemented!("flash only exists on the board");
+    }
+
+    pub(crate) fn ecall1(_syscall_number: u8, _arg1: u32) {
+        unimplemented!("system calls only work on the board");
+    }
+}
@@ -0,0 +1,654 @@
+use crate::{
+    bios_interface::{ecall1, sleep_unless_terminated, Terminated},
+    edit_line::EditLine,
+    editor, elf, exit_status,
+    file_system::FileSystem,
+    memory, parse_number, put, putn, run_program, script, sys_call,
+    variables::Variables,
+    xmodem::{receive_batch, receive_file, send_batch, send_file},
+};
+
+/// Everything a command can act on.
+pub(crate) struct Shell<'a> {
+    pub(crate) file_system: &'a mut &'static mut FileSystem,
+    pub(crate) edit_line: &'a mut EditLine,
+    pub(crate) variables: &'a mut Variables,
+    /// How many scripts are being run inside each other.
+    pub(crate) script_depth: usize,
+}
+
+pub(crate) enum CommandError {
+    /// The arguments were wrong, the shell prints the command's usage.
+    Usage,
+    /// The command failed and already said why.
+    Failed,
+    /// The command or the program it ran was stopped with Ctrl-C.
+    Terminated,
+    /// The program ran, but exited with a non-zero exit code.
+    Exited(i32),
+}
+
+impl From<Terminated> for CommandError {
+    fn from(_: Terminated) -> Self {
+        CommandError::Terminated
+    }
+}
+
+pub(crate) struct Command {
+    pub(crate) name: &'static [u8],
+    pub(crate) aliases: &'static [&'static [u8]],
+    pub(crate) usage: &'static str,
+    pub(crate) description: &'static str,
+    pub(crate) min_args: usize,
+    pub(crate) max_args: usize,
+    pub(crate) handler: fn(&mut Shell, &[&[u8]]) -> Result<(), CommandError>,
+}
+
+pub(crate) static COMMANDS: &[Command] = &[
+    Command {
+        name: b"help",
+        aliases: &[],
+        usage: "help [command]",
+        description: "List the commands, or show how to use one",
+        min_args: 0,
+        max_args: 1,
+        handler: help,
+    },
+    Command {
+        name: b"ecall",
+        aliases: &[],
+        usage: "ecall <argument>",
+        description: "Call system interrupt 1 with an argument",
+        min_args: 1,
+        max_args: 1,
+        handler: ecall,
+    },
+    Command {
+        name: b"leds",
+        aliases: &[],
+        usage: "leds [r][g][b]",
+        description: "Turn on the given LEDs and the others off",
+        min_args: 0,
+        max_args: 1,
+        handler: leds,
+    },
+    Command {
+        name: b"delay",
+        aliases: &[],
+        usage: "delay <milliseconds>",
+        description: "Wait for a while",
+        min_args: 1,
+        max_args: 1,
+        handler: delay,
+    },
+    Command {
+        name: b"run",
+        aliases: &[],
+        usage: "run <file> [args...]",
+        description: "Run an ELF program with arguments",
+        min_args: 1,
+        max_args: usize::MAX,
+        handler: run,
+    },
+    Command {
+        name: b"exec",
+        aliases: &[],
+        usage: "exec <address>",
+        description: "Jump to an address as a new program",
+        min_args: 1,
+        max_args: 1,
+        handler: exec,
+    },
+    Command {
+        name: b"exit",
+        aliases: &[],
+        usage: "exit <code>",
+        description: "Return from the current program",
+        min_args: 1,
+        max_args: 1,
+        handler: exit,
+    },
+    Command {
+        name: b"fs",
+        aliases: &[],
+        usage: "fs <stats|save|load|reset>",
+        description: "Manage the file system",
+        min_args: 1,
+        max_args: 1,
+        handler: fs,
+    },
+    Command {
+        name: b"write",
+        aliases: &[b"create"],
+        usage: "write <file> <content...>",
+        description: "Create a file with the remaining words as content",
+        min_args: 1,
+        max_args: usize::MAX,
+        handler: write,
+    },
+    Command {
+        name: b"edit",
+        aliases: &[],
+        usage: "edit <file>",
+        description: "Edit a text file line by line",
+        min_args: 1,
+        max_args: 1,
+        handler: edit,
+    },
+    Command {
+        name: b"ls",
+        aliases: &[],
+        usage: "ls [file]",
+        description: "List files",
+        min_args: 0,
+        max_args: 1,
+        handler: ls,
+    },
+    Command {
+        name: b"cat",
+        aliases: &[b"read"],
+        usage: "cat <file>",
+        description: "Print a file",
+        min_args: 1,
+        max_args: 1,
+        handler: cat,
+    },
+    Command {
+        name: b"rm",
+        aliases: &[],
+        usage: "rm <file>",
+        description: "Remove a file",
+        min_args: 1,
+        max_args: 1,
+        handler: rm,
+    },
+    Command {
+        name: b"history",
+        aliases: &[],
+        usage: "history [save]",
+        description: "List recent commands, or save them for the next boot",
+        min_args: 0,
+        max_args: 1,
+        handler: history,
+    },
+    Command {
+        name: b"hexdump",
+        aliases: &[],
+        usage: "hexdump <file>",
+        description: "Print a file as hex and ASCII",
+        min_args: 1,
+        max_args: 1,
+        handler: hexdump,
+    },
+    Command {
+        name: b"md",
+        aliases: &[],
+        usage: "md <address> [length]",
+        description: "Dump memory as hex and ASCII",
+        min_args: 1,
+        max_args: 2,
+        handler: md,
+    },
+    Command {
+        name: b"mw",
+        aliases: &[],
+        usage: "mw <address> <value> [8|16|32]",
+        description: "Write a byte, half-word or word to memory",
+        min_args: 2,
+        max_args: 3,
+        handler: mw,
+    },
+    Command {
+        name: b"set",
+        aliases: &[],
+        usage: "set [<name> <value> | save]",
+        description: "List or set variables, or save them for the next boot",
+        min_args: 0,
+        max_args: 2,
+        handler: set,
+    },
+    Command {
+        name: b"unset",
+        aliases: &[],
+        usage: "unset <name>",
+        description: "Remove a variable",
+        min_args: 1,
+        max_args: 1,
+        handler: unset,
+    },
+    Command {
+        name: b"source",
+        aliases: &[b"."],
+        usage: "source <file>",
+        description: "Run each line of a file as a command",
+        min_args: 1,
+        max_args: 1,
+        handler: source,
+    },
+    Command {
+        name: b"rx",
+        aliases: &[],
+        usage: "rx <file> [size]",
+        description: "Receive a file over XMODEM",
+        min_args: 1,
+        max_args: 2,
+        handler: rx,
+    },
+    Command {
+        name: b"ry",
+        aliases: &[],
+        usage: "ry",
+        description: "Receive any number of files over YMODEM",
+        min_args: 0,
+        max_args: 0,
+        handler: ry,
+    },
+    Command {
+        name: b"sx",
+        aliases: &[],
+        usage: "sx <file>",
+        description: "Send a file over XMODEM",
+        min_args: 1,
+        max_args: 1,
+        handler: sx,
+    },
+    Command {
+        name: b"sy",
+        aliases: &[],
+        usage: "sy <file>",
+        description: "Send a file over YMODEM",
+        min_args: 1,
+        max_args: 1,
+        handler: sy,
+    },
+    Command {
+        name: b"paste",
+        aliases: &[],
+        usage: "paste <file> <size>",
+        description: "Create a file from the next <size> bytes typed or pasted",
+        min_args: 2,
+        max_args: 2,
+        handler: paste,
+    },
+];
+
+pub(crate) fn find_command(name: &[u8]) -> Option<&'static Command> {
+    COMMANDS
+        .iter()
+        .find(|command| command.name == name || command.aliases.contains(&name))
+}
+
+/// All names that commands can be called by, including aliases.
+pub(crate) fn command_names() -> impl Iterator<Item = &'static [u8]> {
+    COMMANDS
+        .iter()
+        .flat_map(|command| core::iter::once(command.name).chain(command.aliases.iter().copied()))
+}
+
+fn number_arg(arg: &[u8]) -> Result<u32, CommandError> {
+    parse_number(arg).map_err(|err| {
+        put!("Invalid number:", arg, "-", err);
+        CommandError::Failed
+    })
+}
+
+/// Like `number_arg`, for sizes and durations which cannot be negative.
+fn unsigned_arg(arg: &[u8]) -> Result<u32, CommandError> {
+    if arg.starts_with(b"-") {
+        put!("Invalid number:", arg, "- Must not be negative.");
+        return Err(CommandError::Failed);
+    }
+
+    number_arg(arg)
+}
+
+fn help(_shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    let name = match args {
+        [name] => *name,
+        _ => {
+            for command in COMMANDS {
+                putn!(command.name);
+                for _ in command.name.len()..10 {
+                    putn!(" ");
+                }
+                put!(command.description);
+            }
+
+            put!();
+            put!("Type 'help <command>' for more.");
+            return Ok(());
+        }
+    };
+
+    let command = match find_command(name) {
+        Some(command) => command,
+        None => {
+            put!("Unknown command:", name);
+            return Err(CommandError::Failed);
+        }
+    };
+
+    put!("Usage:", command.usage);
+    put!(command.description);
+
+    if !command.aliases.is_empty() {
+        putn!("Aliases:");
+        for &alias in command.aliases {
+            putn!(" ", alias);
+        }
+        put!();
+    }
+
+    Ok(())
+}
+
+fn ecall(_shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    let arg1 = number_arg(args[0])?;
+
+    put!("Calling system interrupt...");
+    ecall1(1, arg1);
+    put!("Back to Rust now.");
+
+    Ok(())
+}
+
+fn leds(_shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    sys_call::set_leds(args.first().copied().unwrap_or(b""));
+
+    Ok(())
+}
+
+fn delay(_shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    let milliseconds = unsigned_arg(args[0])?;
+    sleep_unless_terminated(milliseconds)?;
+
+    Ok(())
+}
+
+fn run(shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    run_program(shell.file_system, shell.variables, args)
+}
+
+fn exec(shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    let address = number_arg(args[0])?;
+
+    // The program may still look for arguments, so give it an empty list
+    let arguments = match elf::write_arguments(&[], shell.variables) {
+        Ok(arguments) => arguments,
+        Err(err) => {
+            put!("Cannot run program:", err);
+            return Err(CommandError::Failed);
+        }
+    };
+
+    let (exit_code, terminated) = sys_call::exec(address, arguments.argc, arguments.argv, arguments.envp);
+
+    exit_status(exit_code, terminated)
+}
+
+fn exit(_shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    let exit_code = number_arg(args[0])?;
+    sys_call::exit(exit_code);
+
+    Ok(())
+}
+
+fn fs(shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    match args[0] {
+        b"stats" => {
+            shell.file_system.print_stats();
+        }
+        b"save" => {
+            shell.file_system.save_file_system();
+        }
+        b"load" => {
+            *shell.file_system = FileSystem::load_from_flash();
+        }
+        b"reset" => {
+            *shell.file_system = FileSystem::new_from_scratch();
+        }
+        subcommand => {
+            put!("Unknown argument:", subcommand);
+            return Err(CommandError::Usage);
+        }
+    }
+
+    Ok(())
+}
+
+fn write(shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    // Words after the file name are joined back together with single spaces
+    let mut content = [0; 256];
+    let mut length = 0;
+
+    for (index, word) in args[1..].iter().enumerate() {
+        let separator = (index > 0) as usize;
+
+        if length + separator + word.len() > content.len() {
+            put!("Content too long, at most", content.len() as i32, "bytes.");
+            return Err(CommandError::Failed);
+        }
+
+        if index > 0 {
+            content[length] = b' ';
+            length += 1;
+        }
+
+        content[length..length + word.len()].copy_from_slice(word);
+        length += word.len();
+    }
+
+    if shell.file_system.create_file(args[0], &content[..length]).is_none() {
+        put!("No file created.");
+        return Err(CommandError::Failed);
+    }
+
+    Ok(())
+}
+
+fn edit(shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    if let Err(err) = editor::edit(shell.file_system, args[0]) {
+        put!("Cannot edit file:", err);
+        return Err(CommandError::Failed);
+    }
+
+    Ok(())
+}
+
+fn ls(shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    for file in shell.file_system.list_files() {
+        let file_name = shell.file_system.file_name(file);
+        if args.is_empty() || file_name == args[0] {
+            put!(file_name);
+        }
+    }
+
+    Ok(())
+}
+
+fn cat(shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    let file_name = args[0];
+
+    let file = match shell.file_system.file(file_name) {
+        Some(file) => file,
+        None => {
+            put!("File not found:", file_name);
+            return Err(CommandError::Failed);
+        }
+    };
+
+    for chunk in shell.file_system.read_file(file) {
+        putn!(chunk);
+    }
+    put!();
+
+    Ok(())
+}
+
+fn rm(shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    let file_name = args[0];
+
+    let file = match shell.file_system.file(file_name) {
+        Some(file) => file,
+        None => {
+            put!("File not found:", file_name);
+            return Err(CommandError::Failed);
+        }
+    };
+
+    shell.file_system.remove_file(file);
+
+    Ok(())
+}
+
+fn history(shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    match args.first().copied().unwrap_or(b"") {
+        b"" => {
+            for entry in shell.edit_line.history() {
+                put!(entry);
+            }
+        }
+        b"save" => {
+            shell.edit_line.save_history(shell.file_system);
+        }
+        subcommand => {
+            put!("Unknown argument:", subcommand);
+            return Err(CommandError::Usage);
+        }
+    }
+
+    Ok(())
+}
+
+fn hexdump(shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    let file_name = args[0];
+
+    let file = match shell.file_system.file(file_name) {
+        Some(file) => file,
+        None => {
+            put!("File not found:", file_name);
+            return Err(CommandError::Failed);
+        }
+    };
+
+    memory::dump(0, shell.file_system.read_file(file).flatten().copied());
+
+    Ok(())
+}
+
+fn md(_shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    let address = number_arg(args[0])?;
+    let length = match args.get(1) {
+        Some(length) => unsigned_arg(length)?,
+        None => 64,
+    };
+
+    // Memory is read a word at a time
+    let length = match length.checked_next_multiple_of(4) {
+        Some(length) => length,
+        None => {
+            put!("Length too large.");
+            return Err(CommandError::Failed);
+        }
+    };
+
+    if let Err(err) = memory::check_access(address, length, 4, false) {
+        put!("Cannot read memory:", err);
+        return Err(CommandError::Failed);
+    }
+
+    memory::dump(address, memory::read_words(address, length));
+
+    Ok(())
+}
+
+fn mw(_shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    let address = number_arg(args[0])?;
+    let value = number_arg(args[1])?;
+    let width = match args.get(2).copied() {
+        Some(b"8") => 8,
+        Some(b"16") => 16,
+        Some(b"32") | None => 32,
+        Some(width) => {
+            put!("Invalid width:", width);
+            return Err(CommandError::Usage);
+        }
+    };
+
+    if width < 32 && value >> width != 0 && (value as i32) >> (width - 1) != -1 {
+        put!("Value does not fit in", width as i32, "bits.");
+        return Err(CommandError::Failed);
+    }
+
+    if let Err(err) = memory::check_access(address, width / 8, width / 8, true) {
+        put!("Cannot write memory:", err);
+        return Err(CommandError::Failed);
+    }
+
+    memory::write(address, value, width);
+
+    Ok(())
+}
+
+fn set(shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    match args {
+        [] => {
+            for (name, value) in shell.variables.iter() {
+                putn!(name, "=");
+                put!(value);
+            }
+        }
+        [b"save"] => {
+            shell.variables.save(shell.file_system);
+        }
+        [name, value] => {
+            if let Err(err) = shell.variables.set(name, value) {
+                put!("Cannot set variable:", err);
+                return Err(CommandError::Failed);
+            }
+        }
+        _ => return Err(CommandError::Usage),
+    }
+
+    Ok(())
+}
+
+fn unset(shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    if !shell.variables.remove(args[0]) {
+        put!("No such variable:", args[0]);
+        return Err(CommandError::Failed);
+    }
+
+    Ok(())
+}
+
+fn source(shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    script::run_script(shell, args[0])
+}
+
+fn rx(shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    let file_size = match args.get(1) {
+        Some(file_size) => Some(unsigned_arg(file_size)? as usize),
+        None => None,
+    };
+
+    receive_file(shell.file_system, args[0], file_size)
+}
+
+fn ry(shell: &mut Shell, _args: &[&[u8]]) -> Result<(), CommandError> {
+    receive_batch(shell.file_system)
+}
+
+fn sx(shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    send_file(shell.file_system, args[0])
+}
+
+fn sy(shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    send_batch(shell.file_system, args[0])
+}
+
+fn paste(shell: &mut Shell, args: &[&[u8]]) -> Result<(), CommandError> {
+    let file_size = unsigned_arg(args[1])? as usize;
+
+    shell.file_system.paste_file(args[0], file_size)
+}
@@ -0,0 +1,69 @@
+use crate::{
+    commands::command_names, edit_line::EditLine, file_system::FileSystem, put, put_prompt, putn,
+};
+
+/// Completes the word at the cursor from the command names if it's the first
+/// word, or from the file names otherwise.
+///
+/// Ambiguous words are completed as far as all candidates agree, and pressing
+/// Tab again lists the candidates.
+pub(crate) fn complete(edit_line: &mut EditLine, file_system: &FileSystem, repeated: bool) {
+    let (word, is_first_word) = edit_line.word_at_cursor();
+    let word_length = word.len();
+
+    let mut count = 0;
+    let mut common_prefix: &[u8] = b"";
+
+    for candidate in candidates(file_system, word, is_first_word) {
+        common_prefix = if count == 0 {
+            candidate
+        } else {
+            let common_length = common_prefix
+                .iter()
+                .zip(candidate)
+                .take_while(|(a, b)| a == b)
+                .count();
+            &common_prefix[..common_length]
+        };
+        count += 1;
+    }
+
+    match count {
+        0 => putn!(7u8),
+        1 => {
+            edit_line.insert_text(&common_prefix[word_length..]);
+            edit_line.insert_text(b" ");
+        }
+        _ if common_prefix.len() > word_length => {
+            edit_line.insert_text(&common_prefix[word_length..]);
+        }
+        _ if repeated => {
+            put!();
+            for candidate in candidates(file_system, word, is_first_word) {
+                putn!(candidate, "  ");
+            }
+            put!();
+            put_prompt();
+            edit_line.redraw();
+        }
+        _ => (),
+    }
+}
+
+fn candidates<'a: 'w, 'w>(
+    file_system: &'a FileSystem,
+    word: &'w [u8],
+    is_first_word: bool,
+) -> impl Iterator<Item = &'a [u8]> + 'w {
+    let commands = command_names()
+        .map(|name| -> &'a [u8] { name })
+        .filter(move |_| is_first_word);
+    let files = file_system
+        .list_files()
+        .map(|file| file_system.file_name(file))
+        .filter(move |_| !is_first_word);
+
+    commands
+        .chain(files)
+        .filter(move |candidate| candidate.starts_with(word))
+}
@@ -1,11 +1,27 @@
-use crate::{bios_interface::put_char, put, putn};
+use crate::{file_system::FileSystem, put, putn};
+
+const HISTORY_SIZE: usize = 8;
+const HISTORY_FILE: &[u8] = b".history";
 
 pub(crate) struct EditLine {
     command: [u8; 256],
     cursor: usize,
+    length: usize,
     inside_escape_code: bool,
     escape_code: [u8; 16],
     escape_cursor: usize,
+    history: History,
+    last_was_tab: bool,
+}
+
+/// Ring of the most recently entered commands.
+struct History {
+    entries: [[u8; 256]; HISTORY_SIZE],
+    lengths: [usize; HISTORY_SIZE],
+    count: usize,
+    next: usize,
+    // How far back Up has gone, with 0 being the most recent entry
+    browsing: Option<usize>,
 }
 
 impl EditLine {
@@ -13,24 +29,132 @@ impl EditLine {
         Self {
             command: [0; 256],
             cursor: 0,
+            length: 0,
             inside_escape_code: false,
             escape_code: [0; 16],
             escape_cursor: 0,
+            history: History {
+                entries: [[0; 256]; HISTORY_SIZE],
+                lengths: [0; HISTORY_SIZE],
+                count: 0,
+                next: 0,
+                browsing: None,
+            },
+            last_was_tab: false,
         }
     }
 
-    pub(crate) fn input_character(&mut self, c: u8) -> Option<EditLineEvent> {
+    pub(crate) fn input_character(&mut self, c: u8) -> Option<EditLineEvent<'_>> {
         process_character(self, c)
     }
+
+    /// Returns the word that ends at the cursor, and whether it is the first
+    /// word on the line.
+    pub(crate) fn word_at_cursor(&self) -> (&[u8], bool) {
+        let before_cursor = &self.command[..self.cursor];
+        let word_start = before_cursor
+            .iter()
+            .rposition(|&byte| byte == b' ')
+            .map_or(0, |index| index + 1);
+        let is_first_word = before_cursor[..word_start].iter().all(|&byte| byte == b' ');
+
+        (&before_cursor[word_start..], is_first_word)
+    }
+
+    pub(crate) fn insert_text(&mut self, text: &[u8]) {
+        for &c in text {
+            insert(self, c);
+        }
+    }
+
+    /// Prints the line being edited again, e.g. after clearing the screen.
+    pub(crate) fn redraw(&self) {
+        putn!(&self.command[..self.length]);
+        move_left(self.length - self.cursor);
+    }
+
+    /// Returns the remembered commands, oldest first.
+    pub(crate) fn history(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        (0..self.history.count).rev().map(|age| self.history.entry(age))
+    }
+
+    /// Loads the history saved with `save_history`, if any.
+    pub(crate) fn load_history(&mut self, file_system: &FileSystem) {
+        let file = match file_system.file(HISTORY_FILE) {
+            Some(file) => file,
+            None => return,
+        };
+
+        let mut line = [0; 256];
+        let mut length = 0;
+
+        for &byte in file_system.read_file(file).flatten() {
+            if byte == b'\n' {
+                self.history.push(&line[..length]);
+                length = 0;
+            } else if length < line.len() {
+                line[length] = byte;
+                length += 1;
+            }
+        }
+    }
+
+    /// Saves the history into a file, so that it survives a reboot.
+    ///
+    /// This is not done after every command since it rewrites flash pages.
+    pub(crate) fn save_history(&self, file_system: &mut FileSystem) {
+        let mut writer = match file_system.create_writer(HISTORY_FILE) {
+            Some(writer) => writer,
+            None => {
+                put!("No free blocks left.");
+                return;
+            }
+        };
+
+        for entry in self.history() {
+            if writer.write(entry).is_none() || writer.write(b"\n").is_none() {
+                put!("File system full, history not saved.");
+                return;
+            }
+        }
+
+        writer.finish();
+    }
+}
+
+impl History {
+    fn push(&mut self, command: &[u8]) {
+        if command.is_empty() || (self.count > 0 && self.entry(0) == command) {
+            return;
+        }
+
+        let length = command.len().min(256);
+        self.entries[self.next][..length].copy_from_slice(&command[..length]);
+        self.lengths[self.next] = length;
+
+        self.next = (self.next + 1) % HISTORY_SIZE;
+        self.count = (self.count + 1).min(HISTORY_SIZE);
+    }
+
+    fn entry(&self, age: usize) -> &[u8] {
+        let index = (self.next + HISTORY_SIZE - 1 - age % HISTORY_SIZE) % HISTORY_SIZE;
+        &self.entries[index][..self.lengths[index]]
+    }
 }
 
 pub(crate) enum EditLineEvent<'a> {
     Command(&'a [u8]),
     EscapeCode(&'a [u8]),
+    ClearScreen,
+    // Tab was pressed; `repeated` if it was also the previous key
+    Complete { repeated: bool },
     UnrecognizedCode(u8),
 }
 
-pub(crate) fn process_character(edit_line: &mut EditLine, input_character: u8) -> Option<EditLineEvent> {
+pub(crate) fn process_character(edit_line: &mut EditLine, input_character: u8) -> Option<EditLineEvent<'_>> {
+    let last_was_tab = edit_line.last_was_tab;
+    edit_line.last_was_tab = input_character == b'\t';
+
     match input_character {
         c if edit_line.inside_escape_code => {
             unsafe {
@@ -38,48 +162,231 @@ pub(crate) fn process_character(edit_line: &mut EditLine, input_character: u8) -
             }
             edit_line.escape_cursor += 1;
 
-            if c.is_ascii_alphabetic() || edit_line.escape_cursor > 15 {
+            if escape_code_complete(&edit_line.escape_code[..edit_line.escape_cursor]) {
                 edit_line.inside_escape_code = false;
-                let escape_code = unsafe {
-                    edit_line.escape_code.get_unchecked(..edit_line.escape_cursor)
-                };
+                let length = edit_line.escape_cursor;
                 edit_line.escape_cursor = 0;
+
+                if process_editing_key(edit_line, length) {
+                    return None;
+                }
+
+                let escape_code = unsafe { edit_line.escape_code.get_unchecked(..length) };
                 Some(EditLineEvent::EscapeCode(escape_code))
             } else {
                 None
             }
         }
         c if is_printable(c) => {
-            unsafe { *edit_line.command.get_unchecked_mut(edit_line.cursor) = c };
-            edit_line.cursor += 1;
-            put_char(c);
+            insert(edit_line, c);
             None
         }
         b'\r' => {
             put!();
-            let command = unsafe { edit_line.command.get_unchecked(..edit_line.cursor) };
+            let command = unsafe { edit_line.command.get_unchecked(..edit_line.length) };
+            edit_line.history.push(command);
+            edit_line.history.browsing = None;
             edit_line.cursor = 0;
+            edit_line.length = 0;
             Some(EditLineEvent::Command(command))
         }
+        b'\t' => Some(EditLineEvent::Complete {
+            repeated: last_was_tab,
+        }),
         8 | 127 => {
             // Backspace
             if edit_line.cursor > 0 {
-                edit_line.cursor -= 1;
-                putn!(8u8, b' ', 8u8);
+                delete(edit_line, edit_line.cursor - 1, edit_line.cursor);
             }
             None
         }
+        1 => {
+            // Ctrl-A
+            move_cursor(edit_line, 0);
+            None
+        }
+        5 => {
+            // Ctrl-E
+            move_cursor(edit_line, edit_line.length);
+            None
+        }
+        11 => {
+            // Ctrl-K, delete until the end of the line
+            delete(edit_line, edit_line.cursor, edit_line.length);
+            None
+        }
+        12 => {
+            // Ctrl-L
+            Some(EditLineEvent::ClearScreen)
+        }
+        21 => {
+            // Ctrl-U, delete until the start of the line
+            delete(edit_line, 0, edit_line.cursor);
+            None
+        }
+        23 => {
+            // Ctrl-W, delete the previous word
+            let before_cursor = &edit_line.command[..edit_line.cursor];
+            let word_end = before_cursor
+                .iter()
+                .rposition(|&byte| byte != b' ')
+                .map_or(0, |index| index + 1);
+            let word_start = before_cursor[..word_end]
+                .iter()
+                .rposition(|&byte| byte == b' ')
+                .map_or(0, |index| index + 1);
+            delete(edit_line, word_start, edit_line.cursor);
+            None
+        }
         27 => {
             edit_line.inside_escape_code = true;
             None
         }
         c => {
             edit_line.cursor = 0;
+            edit_line.length = 0;
             Some(EditLineEvent::UnrecognizedCode(c))
         },
     }
 }
 
+/// Escape codes are either `[`, optional parameters and a final letter or
+/// `~`, or `O` and a single letter (as sent for Home and End by some
+/// terminals).
+fn escape_code_complete(escape_code: &[u8]) -> bool {
+    match escape_code {
+        [b'['] | [b'O'] => false,
+        [b'[', .., last] => (0x40..=0x7E).contains(last),
+        [b'O', _] => true,
+        [_] => true,
+        _ => escape_code.len() > 15,
+    }
+}
+
+/// Handles cursor movement and history keys, returning whether the escape
+/// code was one of them.
+fn process_editing_key(edit_line: &mut EditLine, length: usize) -> bool {
+    match &edit_line.escape_code[..length] {
+        b"[A" => {
+            // Up key
+            let age = edit_line.history.browsing.map_or(0, |age| age + 1);
+            if age < edit_line.history.count {
+                edit_line.history.browsing = Some(age);
+                recall(edit_line, age);
+            }
+        }
+        b"[B" => {
+            // Down key
+            match edit_line.history.browsing {
+                Some(0) => {
+                    edit_line.history.browsing = None;
+                    replace_line(edit_line, b"");
+                }
+                Some(age) => {
+                    edit_line.history.browsing = Some(age - 1);
+                    recall(edit_line, age - 1);
+                }
+                None => (),
+            }
+        }
+        b"[D" => {
+            // Left key
+            move_cursor(edit_line, edit_line.cursor.saturating_sub(1));
+        }
+        b"[C" => {
+            // Right key
+            move_cursor(edit_line, (edit_line.cursor + 1).min(edit_line.length));
+        }
+        b"[H" | b"[1~" | b"[7~" | b"OH" => {
+            // Home key
+            move_cursor(edit_line, 0);
+        }
+        b"[F" | b"[4~" | b"[8~" | b"OF" => {
+            // End key
+            move_cursor(edit_line, edit_line.length);
+        }
+        b"[3~" => {
+            // Delete key
+            if edit_line.cursor < edit_line.length {
+                delete(edit_line, edit_line.cursor, edit_line.cursor + 1);
+            }
+        }
+        _ => return false,
+    }
+
+    true
+}
+
+fn insert(edit_line: &mut EditLine, c: u8) {
+    let cursor = edit_line.cursor;
+    let length = edit_line.length;
+
+    if length == edit_line.command.len() {
+        return;
+    }
+
+    edit_line.command.copy_within(cursor..length, cursor + 1);
+    edit_line.command[cursor] = c;
+    edit_line.length += 1;
+    edit_line.cursor += 1;
+
+    // Redraw the rest of the line, then go back to just after the new character
+    putn!(&edit_line.command[cursor..edit_line.length]);
+    move_left(edit_line.length - edit_line.cursor);
+}
+
+/// Removes the characters from `start` to `end` and leaves the cursor at `start`.
+fn delete(edit_line: &mut EditLine, start: usize, end: usize) {
+    if start >= end {
+        return;
+    }
+
+    move_cursor(edit_line, start);
+
+    let length = edit_line.length;
+    edit_line.command.copy_within(end..length, start);
+    edit_line.length -= end - start;
+
+    // Redraw the rest of the line and blank out what's left over of the old one
+    putn!(&edit_line.command[start..edit_line.length], "\x1b[K");
+    move_left(edit_line.length - start);
+}
+
+fn move_cursor(edit_line: &mut EditLine, position: usize) {
+    if position < edit_line.cursor {
+        move_left(edit_line.cursor - position);
+    } else if position > edit_line.cursor {
+        putn!(&edit_line.command[edit_line.cursor..position]);
+    }
+
+    edit_line.cursor = position;
+}
+
+fn move_left(columns: usize) {
+    if columns > 0 {
+        putn!("\x1b[", columns as i32, "D");
+    }
+}
+
+fn recall(edit_line: &mut EditLine, age: usize) {
+    let mut entry = [0; 256];
+    let source = edit_line.history.entry(age);
+    let length = source.len();
+    entry[..length].copy_from_slice(source);
+
+    replace_line(edit_line, &entry[..length]);
+}
+
+/// Erases what was typed so far and shows `content` instead.
+fn replace_line(edit_line: &mut EditLine, content: &[u8]) {
+    move_left(edit_line.cursor);
+    putn!("\x1b[K", content);
+
+    edit_line.command[..content.len()].copy_from_slice(content);
+    edit_line.cursor = content.len();
+    edit_line.length = content.len();
+}
+
 fn is_printable(c: u8) -> bool {
     c.is_ascii_alphanumeric() || c.is_ascii_digit() || c == b' ' || c.is_ascii_punctuation()
 }
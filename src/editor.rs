@@ -0,0 +1,277 @@
+use core::slice;
+
+use crate::{
+    bios_interface::get_char,
+    edit_line::{EditLine, EditLineEvent},
+    file_system::FileSystem,
+    parse_number, put, putn,
+};
+
+// The text being edited is kept in RAM, below the buffer used for redirection;
+// programs use the same region for their data while they run
+const TEXT_BUFFER: *mut u8 = (0x20000000 + 1024) as *mut u8;
+const TEXT_SIZE: usize = 7 * 1024;
+
+const HELP: &[&str] = &[
+    "p [n[,m]]  Print lines, all of them by default",
+    "a [n]      Add lines after line n, or at the end",
+    "i [n]      Insert lines before line n, or at the start",
+    "c n[,m]    Change lines, replacing them with new ones",
+    "d n[,m]    Delete lines",
+    "w          Write the file, wq to also quit",
+    "q          Quit, Q to quit without writing",
+    "When adding lines, a single '.' on a line stops.",
+];
+
+/// Lines of text, each ending with a newline.
+struct Text {
+    bytes: &'static mut [u8],
+    length: usize,
+    modified: bool,
+}
+
+impl Text {
+    fn line_count(&self) -> usize {
+        self.bytes[..self.length].iter().filter(|&&byte| byte == b'\n').count()
+    }
+
+    /// Returns where the line with the given index starts, or the end of the
+    /// text for the index just after the last line.
+    fn line_start(&self, index: usize) -> usize {
+        if index == 0 {
+            return 0;
+        }
+
+        self.bytes[..self.length]
+            .iter()
+            .enumerate()
+            .filter(|&(_, &byte)| byte == b'\n')
+            .nth(index - 1)
+            .map_or(self.length, |(position, _)| position + 1)
+    }
+
+    fn line(&self, index: usize) -> &[u8] {
+        let start = self.line_start(index);
+        let end = self.line_start(index + 1);
+
+        &self.bytes[start..end.saturating_sub(1).max(start)]
+    }
+
+    /// Inserts a line before the line with the given index.
+    fn insert(&mut self, index: usize, line: &[u8]) -> Result<(), &'static str> {
+        if self.length + line.len() + 1 > self.bytes.len() {
+            return Err("Text buffer full.");
+        }
+
+        let start = self.line_start(index);
+        let end = start + line.len() + 1;
+
+        self.bytes.copy_within(start..self.length, end);
+        self.bytes[start..end - 1].copy_from_slice(line);
+        self.bytes[end - 1] = b'\n';
+        self.length += line.len() + 1;
+        self.modified = true;
+
+        Ok(())
+    }
+
+    /// Removes the lines from index `first` to `last`, inclusive.
+    fn delete(&mut self, first: usize, last: usize) {
+        let start = self.line_start(first);
+        let end = self.line_start(last + 1);
+
+        self.bytes.copy_within(end..self.length, start);
+        self.length -= end - start;
+        self.modified = true;
+    }
+}
+
+/// Edits a file with `ed`-like commands, creating it if needed.
+pub(crate) fn edit(file_system: &mut FileSystem, file_name: &[u8]) -> Result<(), &'static str> {
+    let mut text = Text {
+        bytes: unsafe { slice::from_raw_parts_mut(TEXT_BUFFER, TEXT_SIZE) },
+        length: 0,
+        modified: false,
+    };
+
+    if let Some(file) = file_system.file(file_name) {
+        for chunk in file_system.read_file(file) {
+            if text.length + chunk.len() >= TEXT_SIZE {
+                return Err("File too large to edit.");
+            }
+
+            text.bytes[text.length..text.length + chunk.len()].copy_from_slice(chunk);
+            text.length += chunk.len();
+        }
+
+        // Every line ends with a newline, including the last one
+        if text.length > 0 && text.bytes[text.length - 1] != b'\n' {
+            text.bytes[text.length] = b'\n';
+            text.length += 1;
+        }
+
+        put!(file_name, "-", text.line_count() as i32, "lines");
+    } else {
+        put!(file_name, "- new file");
+    }
+
+    put!("Type 'h' for help.");
+
+    let mut edit_line = EditLine::new();
+
+    loop {
+        let mut line = [0; 256];
+        let length = read_line(&mut edit_line, ":", &mut line);
+        let line = &line[..length];
+
+        let (command, range) = match line.split_first() {
+            Some((&command, range)) => (command, range.trim_ascii()),
+            None => continue,
+        };
+
+        let result = match command {
+            b'q' if text.modified => Err("Unsaved changes, use 'w' to write or 'Q' to quit anyway."),
+            b'q' | b'Q' => return Ok(()),
+            b'w' if range == b"q" => {
+                write(file_system, file_name, &mut text)?;
+                return Ok(());
+            }
+            b'w' => write(file_system, file_name, &mut text),
+            b'h' => {
+                for &help in HELP {
+                    put!(help);
+                }
+                Ok(())
+            }
+            b'p' => print_lines(&text, range),
+            b'a' => parse_line(range, text.line_count(), 0, text.line_count())
+                .and_then(|line| add_lines(&mut edit_line, &mut text, line)),
+            b'i' => parse_line(range, 1, 1, text.line_count() + 1)
+                .and_then(|line| add_lines(&mut edit_line, &mut text, line - 1)),
+            b'd' => parse_range(range, text.line_count()).map(|(first, last)| text.delete(first, last)),
+            b'c' => parse_range(range, text.line_count()).and_then(|(first, last)| {
+                text.delete(first, last);
+                add_lines(&mut edit_line, &mut text, first)
+            }),
+            _ => Err("Unknown command, type 'h' for help."),
+        };
+
+        if let Err(err) = result {
+            put!("?", err);
+        }
+    }
+}
+
+fn write(file_system: &mut FileSystem, file_name: &[u8], text: &mut Text) -> Result<(), &'static str> {
+    let mut writer = file_system.create_writer(file_name).ok_or("No free blocks left.")?;
+    writer
+        .write(&text.bytes[..text.length])
+        .ok_or("File system full, file not written.")?;
+    writer.finish();
+
+    put!("Wrote", text.length as i32, "bytes.");
+    text.modified = false;
+
+    Ok(())
+}
+
+fn print_lines(text: &Text, range: &[u8]) -> Result<(), &'static str> {
+    let line_count = text.line_count();
+
+    let (first, last) = if range.is_empty() {
+        if line_count == 0 {
+            return Ok(());
+        }
+        (0, line_count - 1)
+    } else {
+        parse_range(range, line_count)?
+    };
+
+    for index in first..=last {
+        put_line_number(index + 1);
+        put!(text.line(index));
+    }
+
+    Ok(())
+}
+
+/// Reads lines from the user until a single `.`, inserting them starting
+/// before the line with the given index.
+fn add_lines(edit_line: &mut EditLine, text: &mut Text, mut index: usize) -> Result<(), &'static str> {
+    loop {
+        let mut line = [0; 256];
+        put_line_number(index + 1);
+        let length = read_line(edit_line, "", &mut line);
+
+        if &line[..length] == b"." {
+            return Ok(());
+        }
+
+        text.insert(index, &line[..length])?;
+        index += 1;
+    }
+}
+
+/// Parses a 1-based line number from `min` to `max`, or returns `default` if
+/// none was given.
+fn parse_line(argument: &[u8], default: usize, min: usize, max: usize) -> Result<usize, &'static str> {
+    if argument.is_empty() {
+        return Ok(default);
+    }
+
+    let line = parse_number(argument)? as usize;
+
+    if line < min || line > max {
+        return Err("No such line.");
+    }
+
+    Ok(line)
+}
+
+/// Parses `n` or `n,m` into 0-based indices of existing lines.
+fn parse_range(range: &[u8], line_count: usize) -> Result<(usize, usize), &'static str> {
+    let (first, last) = match range.iter().position(|&byte| byte == b',') {
+        Some(comma) => (&range[..comma], &range[comma + 1..]),
+        None => (range, range),
+    };
+
+    let first = parse_number(first.trim_ascii())? as usize;
+    let last = parse_number(last.trim_ascii())? as usize;
+
+    if first == 0 || first > last || last > line_count {
+        return Err("No such lines.");
+    }
+
+    Ok((first - 1, last - 1))
+}
+
+fn put_line_number(number: usize) {
+    for _ in number.max(1).ilog10()..3 {
+        putn!(" ");
+    }
+    putn!(number as i32, "| ");
+}
+
+/// Reads a line with the usual editing keys and returns its length.
+fn read_line(edit_line: &mut EditLine, prompt: &str, line: &mut [u8; 256]) -> usize {
+    putn!(prompt);
+
+    loop {
+        match edit_line.input_character(get_char()) {
+            Some(EditLineEvent::Command(command)) => {
+                line[..command.len()].copy_from_slice(command);
+                return command.len();
+            }
+            Some(EditLineEvent::ClearScreen) => {
+                putn!("\x1b[2J\x1b[H", prompt);
+                edit_line.redraw();
+            }
+            Some(EditLineEvent::UnrecognizedCode(_)) => {
+                // The line was thrown away
+                put!();
+                putn!(prompt);
+            }
+            _ => (),
+        }
+    }
+}
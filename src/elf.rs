@@ -1,84 +1,543 @@
-use crate::put;
+use core::slice;
 
+use crate::variables::Variables;
 
-pub(crate) fn read_elf(contents: &[u8]) -> Result<usize, &'static str> {
-    let elf_header = contents.get(0..54)
-        .ok_or("ELF header too short")?;
+const ELF_HEADER_SIZE: usize = 52;
+const PROGRAM_HEADER_SIZE: usize = 32;
 
-    let magic_header = &elf_header[0..4];
+const ELF_CLASS_32: u8 = 1;
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+const ELF_VERSION_CURRENT: u8 = 1;
 
-    if magic_header != b"\x7FELF" {
-        return Err("Cannot run program: Magic ELF header not found.");
+const ELF_TYPE_EXEC: u16 = 2;
+const ELF_TYPE_DYN: u16 = 3;
+const ELF_MACHINE_RISCV: u16 = 243;
+
+// The core has compressed instructions (RVC) but no floating point unit
+const ELF_FLAGS_FLOAT_ABI: u32 = 0x6;
+
+const PROGRAM_TYPE_LOAD: u32 = 1;
+const PROGRAM_TYPE_DYNAMIC: u32 = 2;
+const PROGRAM_FLAGS_EXECUTE: u32 = 0x1;
+const PROGRAM_FLAGS_WRITE: u32 = 0x2;
+
+const DYNAMIC_NULL: u32 = 0;
+const DYNAMIC_PLT_RELOCATIONS_SIZE: u32 = 2;
+const DYNAMIC_SYMBOL_TABLE: u32 = 6;
+const DYNAMIC_RELOCATIONS: u32 = 7;
+const DYNAMIC_RELOCATIONS_SIZE: u32 = 8;
+const DYNAMIC_RELOCATION_SIZE: u32 = 9;
+const DYNAMIC_PLT_RELOCATIONS: u32 = 23;
+
+const RELOCATION_SIZE: usize = 12;
+const SYMBOL_SIZE: usize = 16;
+
+const RELOCATION_NONE: u32 = 0;
+const RELOCATION_32: u32 = 1;
+const RELOCATION_RELATIVE: u32 = 3;
+const RELOCATION_JUMP_SLOT: u32 = 5;
+
+const SYMBOL_BINDING_WEAK: u8 = 2;
+
+// Symbols with a fixed value, e.g. a hardware address, that isn't moved
+const SECTION_ABSOLUTE: u16 = 0xFFF1;
+
+// Program data is copied here; the editor's text buffer
+// shares it, since the two are never in use at the same time
+const USER_RAM: usize = 0x20000000 + 1024;
+const USER_RAM_SIZE: usize = 6 * 1024 + 512;
+
+// The program's arguments and environment come right after its data
+const ARGUMENTS: usize = USER_RAM + USER_RAM_SIZE;
+const ARGUMENTS_SIZE: usize = 512;
+
+/// A validated ELF file.
+pub(crate) struct Elf<'a> {
+    pub(crate) contents: &'a [u8],
+    pub(crate) entry_point: usize,
+    program_headers: &'a [u8],
+    program_header_size: usize,
+}
+
+/// A PT_LOAD program header, with its part of the file checked to be inside
+/// the file.
+pub(crate) struct Segment {
+    pub(crate) file_offset: usize,
+    pub(crate) virtual_address: usize,
+    pub(crate) file_size: usize,
+    pub(crate) memory_size: usize,
+    pub(crate) writable: bool,
+    pub(crate) executable: bool,
+}
+
+impl Segment {
+    pub(crate) fn contains(&self, virtual_address: usize) -> bool {
+        virtual_address >= self.virtual_address
+            && virtual_address - self.virtual_address < self.memory_size
+    }
+}
+
+pub(crate) fn read_elf(contents: &[u8]) -> Result<Elf<'_>, &'static str> {
+    let elf_header = contents
+        .get(0..ELF_HEADER_SIZE)
+        .ok_or("ELF header too short.")?;
+
+    if &elf_header[0..4] != b"\x7FELF" {
+        return Err("Magic ELF header not found.");
+    }
+
+    if elf_header[4] != ELF_CLASS_32 {
+        return Err("Not 32-bit.");
+    }
+
+    if elf_header[5] != ELF_DATA_LITTLE_ENDIAN {
+        return Err("Not little-endian.");
+    }
+
+    if elf_header[6] != ELF_VERSION_CURRENT {
+        return Err("Unknown ELF version.");
     }
 
-    let bit_format = elf_header[4];
+    match read_u16(elf_header, 0x10) {
+        ELF_TYPE_EXEC | ELF_TYPE_DYN => (),
+        _ => return Err("Not an executable, e.g. an object file that still needs linking."),
+    }
+
+    if read_u16(elf_header, 0x12) != ELF_MACHINE_RISCV {
+        return Err("Not a RISC-V program.");
+    }
+
+    if read_u32(elf_header, 0x24) & ELF_FLAGS_FLOAT_ABI != 0 {
+        return Err("Needs hardware floating point, which this core doesn't have.");
+    }
 
-    if bit_format != 1 {
-        return Err("Cannot run program: Not 32-bit.");
+    if (read_u16(elf_header, 0x28) as usize) < ELF_HEADER_SIZE {
+        return Err("ELF header size too small.");
     }
 
-    let entry_point = read_u32(&elf_header[0x18..0x18+4]) as usize;
+    let entry_point = read_u32(elf_header, 0x18) as usize;
+
+    let program_header_start = read_u32(elf_header, 0x1C) as usize;
+    let program_header_size = read_u16(elf_header, 0x2A) as usize;
+    let program_header_count = read_u16(elf_header, 0x2C) as usize;
 
-    let program_header_start = read_u32(&elf_header[0x1C..0x1C+4]) as usize;
-    let program_header_size = read_u16(&elf_header[0x2A..0x2A+2]) as usize;
-    let program_header_count = read_u16(&elf_header[0x2C..0x2C+2]) as usize;
+    if program_header_count == 0 {
+        return Err("No program headers found.");
+    }
 
-    let program_headers_end = program_header_start + program_header_size * program_header_count;
+    if program_header_size < PROGRAM_HEADER_SIZE {
+        return Err("Program header size too small.");
+    }
 
-    let program_headers = contents.get(program_header_start..program_headers_end)
-        .ok_or("ELF program headers section too short")?;
+    let program_headers_end = program_header_size
+        .checked_mul(program_header_count)
+        .and_then(|size| size.checked_add(program_header_start))
+        .ok_or("Program headers outside the file.")?;
 
-    for index in 0..program_header_count {
-        let header_start = index * program_header_size;
-        let header_end = (index + 1) * program_header_size;
+    let program_headers = contents
+        .get(program_header_start..program_headers_end)
+        .ok_or("Program headers outside the file.")?;
 
-        let program_header = program_headers.get(header_start..header_end)
-            .ok_or("ELF program header outside range")?;
+    let elf = Elf {
+        contents,
+        entry_point,
+        program_headers,
+        program_header_size,
+    };
 
-        if let Some(address) = read_program_header(program_header, entry_point)? {
-            return Ok(address);
+    let mut entry_point_found = false;
+
+    for segment in elf.segments() {
+        let segment = segment?;
+        entry_point_found |= segment.executable && segment.contains(entry_point);
+    }
+
+    if !entry_point_found {
+        return Err("Entry point not inside an executable segment.");
+    }
+
+    Ok(elf)
+}
+
+impl<'a> Elf<'a> {
+    /// Returns the loadable segments, or an error for the first invalid one.
+    pub(crate) fn segments(&self) -> impl Iterator<Item = Result<Segment, &'static str>> + '_ {
+        self.program_headers
+            .chunks_exact(self.program_header_size)
+            .filter(|program_header| read_u32(program_header, 0x00) == PROGRAM_TYPE_LOAD)
+            .map(|program_header| read_segment(program_header, self.contents.len()))
+    }
+
+    /// Returns the contents of the PT_DYNAMIC segment, if there is one.
+    fn dynamic(&self) -> Result<Option<&'a [u8]>, &'static str> {
+        let program_header = match self
+            .program_headers
+            .chunks_exact(self.program_header_size)
+            .find(|program_header| read_u32(program_header, 0x00) == PROGRAM_TYPE_DYNAMIC)
+        {
+            Some(program_header) => program_header,
+            None => return Ok(None),
+        };
+
+        let offset = read_u32(program_header, 0x04) as usize;
+        let size = read_u32(program_header, 0x10) as usize;
+
+        offset
+            .checked_add(size)
+            .and_then(|end| self.contents.get(offset..end))
+            .map(Some)
+            .ok_or("Dynamic section outside the file.")
+    }
+
+    /// Returns the file contents loaded at the given virtual address.
+    fn table(&self, virtual_address: usize, size: usize) -> Result<&'a [u8], &'static str> {
+        let segment = self
+            .segments()
+            .flatten()
+            .find(|segment| segment.contains(virtual_address))
+            .ok_or("Table outside the program.")?;
+
+        let start = virtual_address - segment.virtual_address;
+
+        if start.checked_add(size).is_none_or(|end| end > segment.file_size) {
+            return Err("Table outside the program.");
         }
+
+        Ok(&self.contents[segment.file_offset + start..][..size])
     }
+}
 
-    Err("No program headers found")
+/// Where a loaded program ended up. Segments in flash and in RAM are each
+/// moved by their own bias, so code can only reach across the two through
+/// absolute addresses, e.g. by linking its data for user RAM.
+pub(crate) struct Layout<'a> {
+    elf: &'a Elf<'a>,
+    flash_bias: usize,
+    ram_bias: usize,
 }
 
-fn read_program_header(program_header: &[u8], entry_point: usize) -> Result<Option<usize>, &'static str> {
-    if program_header.len() < 0x14 {
-        return Err("Program header too short");
+impl Layout<'_> {
+    /// Maps an address the program was linked at to where it is at run time.
+    pub(crate) fn runtime_address(&self, virtual_address: usize) -> Option<usize> {
+        self.elf
+            .segments()
+            .flatten()
+            .find(|segment| segment.contains(virtual_address))
+            .map(|segment| virtual_address.wrapping_add(self.bias(&segment)))
+    }
+
+    fn bias(&self, segment: &Segment) -> usize {
+        if in_ram(segment) {
+            self.ram_bias
+        } else {
+            self.flash_bias
+        }
+    }
+
+    /// Applies the dynamic relocations of position-independent programs, which
+    /// can only be patched in the segments copied to RAM.
+    fn relocate(&self) -> Result<(), &'static str> {
+        let dynamic = match self.elf.dynamic()? {
+            Some(dynamic) => dynamic,
+            None => return Ok(()),
+        };
+
+        let mut relocations = (0, 0);
+        let mut plt_relocations = (0, 0);
+        let mut relocation_size = RELOCATION_SIZE;
+        let mut symbol_table = None;
+
+        for entry in dynamic.chunks_exact(8) {
+            let value = read_u32(entry, 4) as usize;
+
+            match read_u32(entry, 0) {
+                DYNAMIC_NULL => break,
+                DYNAMIC_RELOCATIONS => relocations.0 = value,
+                DYNAMIC_RELOCATIONS_SIZE => relocations.1 = value,
+                DYNAMIC_RELOCATION_SIZE => relocation_size = value,
+                DYNAMIC_PLT_RELOCATIONS => plt_relocations.0 = value,
+                DYNAMIC_PLT_RELOCATIONS_SIZE => plt_relocations.1 = value,
+                DYNAMIC_SYMBOL_TABLE => symbol_table = Some(value),
+                _ => (),
+            }
+        }
+
+        if relocation_size != RELOCATION_SIZE {
+            return Err("Unsupported relocation entry size.");
+        }
+
+        for (address, size) in [relocations, plt_relocations] {
+            if size == 0 {
+                continue;
+            }
+
+            for relocation in self.elf.table(address, size)?.chunks_exact(RELOCATION_SIZE) {
+                self.apply_relocation(relocation, symbol_table)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply_relocation(&self, relocation: &[u8], symbol_table: Option<usize>) -> Result<(), &'static str> {
+        let offset = read_u32(relocation, 0) as usize;
+        let info = read_u32(relocation, 4);
+        let addend = read_u32(relocation, 8) as usize;
+
+        let value = match info & 0xFF {
+            RELOCATION_NONE => return Ok(()),
+            // Like symbols, the addend may point past the end of a segment
+            // (e.g. `_end`), so it is only moved, never checked
+            RELOCATION_RELATIVE => addend.wrapping_add(self.relative_bias(addend)),
+            RELOCATION_32 => self.symbol_address(symbol_table, info >> 8)?.wrapping_add(addend),
+            RELOCATION_JUMP_SLOT => self.symbol_address(symbol_table, info >> 8)?,
+            _ => return Err("Unsupported relocation type."),
+        };
+
+        let last_byte = offset.checked_add(3).ok_or("Relocation outside the program.")?;
+
+        let segment = self
+            .elf
+            .segments()
+            .flatten()
+            .find(|segment| segment.contains(offset) && segment.contains(last_byte))
+            .ok_or("Relocation outside the program.")?;
+
+        // Flash can't be patched while loading
+        if !in_ram(&segment) {
+            return Err("Relocation in a segment run from flash.");
+        }
+
+        let target = offset.wrapping_add(self.ram_bias) as *mut u32;
+        unsafe { target.write_unaligned(value as u32) };
+
+        Ok(())
+    }
+
+    /// Returns the bias of the last segment starting at or below the address,
+    /// or of the lowest segment for addresses below all of them.
+    fn relative_bias(&self, virtual_address: usize) -> usize {
+        let mut lowest = None;
+        let mut below = None;
+
+        for segment in self.elf.segments().flatten() {
+            let start_and_bias = (segment.virtual_address, self.bias(&segment));
+
+            if lowest.is_none_or(|(start, _)| segment.virtual_address < start) {
+                lowest = Some(start_and_bias);
+            }
+
+            if segment.virtual_address <= virtual_address
+                && below.is_none_or(|(start, _)| segment.virtual_address >= start)
+            {
+                below = Some(start_and_bias);
+            }
+        }
+
+        below.or(lowest).map_or(self.flash_bias, |(_, bias)| bias)
     }
 
-    let file_offset = read_u32(&program_header[0x04..0x04+4]) as usize;
-    let virtual_offset = read_u32(&program_header[0x08..0x08+4]) as usize;
-    let segment_size = read_u32(&program_header[0x10..0x10+4]) as usize;
+    /// Resolves a symbol against the program itself; there are no shared
+    /// libraries, so only undefined weak symbols are allowed (as zero).
+    fn symbol_address(&self, symbol_table: Option<usize>, index: u32) -> Result<usize, &'static str> {
+        if index == 0 {
+            return Ok(0);
+        }
+
+        let symbol_table = symbol_table.ok_or("Relocation without a symbol table.")?;
+        let symbol_address = (index as usize)
+            .checked_mul(SYMBOL_SIZE)
+            .and_then(|offset| offset.checked_add(symbol_table))
+            .ok_or("Symbol outside the symbol table.")?;
+        let symbol = self.elf.table(symbol_address, SYMBOL_SIZE)?;
+
+        let section = read_u16(symbol, 0x0E);
+        let value = read_u32(symbol, 0x04) as usize;
 
-    if virtual_offset + segment_size < virtual_offset {
-        return Err("Segment size overflow error");
+        if section == SECTION_ABSOLUTE {
+            Ok(value)
+        } else if section != 0 {
+            Ok(value.wrapping_add(self.relative_bias(value)))
+        } else if symbol[0x0C] >> 4 == SYMBOL_BINDING_WEAK {
+            Ok(0)
+        } else {
+            Err("Program needs a symbol from a shared library.")
+        }
     }
+}
+
+/// Copies the segments that belong in RAM (see `in_ram`) into user RAM,
+/// zeroing everything not loaded from the file (e.g. `.bss`), and applies any
+/// dynamic relocations. All other segments run in place from flash at
+/// `flash_address`.
+pub(crate) fn load<'a>(elf: &'a Elf<'a>, flash_address: usize) -> Result<Layout<'a>, &'static str> {
+    let mut flash_bias = None;
+    let mut start = usize::MAX;
+    let mut end = 0;
+
+    for segment in elf.segments().flatten().filter(|segment| segment.memory_size > 0) {
+        if in_ram(&segment) {
+            start = start.min(segment.virtual_address);
+            end = end.max(segment.virtual_address + segment.memory_size);
+            continue;
+        }
 
-    let virtual_segment = virtual_offset..virtual_offset + segment_size;
+        // Code in flash only works if it is as far from the rest of the flash
+        // segments as it is in the file
+        let segment_bias = flash_address
+            .wrapping_add(segment.file_offset)
+            .wrapping_sub(segment.virtual_address);
 
-    put!("Checking:", entry_point as i32, file_offset as i32, virtual_offset as i32, segment_size as i32);
+        if *flash_bias.get_or_insert(segment_bias) != segment_bias {
+            return Err("Segments not laid out as in the file.");
+        }
+    }
 
-    if virtual_segment.contains(&entry_point) {
-        Ok(Some(entry_point + file_offset - virtual_offset))
+    // Data linked for user RAM stays where it is, other data is moved there
+    let ram_bias = if start >= end || (start >= USER_RAM && end <= USER_RAM + USER_RAM_SIZE) {
+        0
+    } else if end - start <= USER_RAM_SIZE {
+        USER_RAM.wrapping_sub(start)
     } else {
-        Ok(None)
+        return Err("Program data does not fit in RAM.");
+    };
+
+    if start < end {
+        let ram = unsafe { slice::from_raw_parts_mut(start.wrapping_add(ram_bias) as *mut u8, end - start) };
+        ram.fill(0);
+
+        for segment in elf.segments().flatten().filter(|segment| segment.memory_size > 0 && in_ram(segment)) {
+            let segment_start = segment.virtual_address - start;
+
+            ram[segment_start..][..segment.file_size]
+                .copy_from_slice(&elf.contents[segment.file_offset..][..segment.file_size]);
+        }
     }
+
+    let layout = Layout {
+        elf,
+        flash_bias: flash_bias.unwrap_or(flash_address),
+        ram_bias,
+    };
+
+    layout.relocate()?;
+
+    Ok(layout)
+}
+
+/// Whether a segment is copied to RAM: writable ones have to be, and so do
+/// read-only ones linked for user RAM, e.g. `.rodata` of a program that
+/// addresses all of its data absolutely.
+fn in_ram(segment: &Segment) -> bool {
+    segment.writable
+        || (segment.virtual_address >= USER_RAM
+            && segment.virtual_address + segment.memory_size <= USER_RAM + USER_RAM_SIZE)
 }
 
-fn read_u16(bytes: &[u8]) -> u16 {
+/// The argument list a program starts with, as passed to `exec`.
+pub(crate) struct Arguments {
+    pub(crate) argc: u32,
+    pub(crate) argv: u32,
+    pub(crate) envp: u32,
+}
+
+/// Copies the arguments into user RAM as the `argv` pointers ending with a
+/// null one, then the `envp` pointers to `NAME=value` strings for the shell
+/// variables, ending the same way, then the null-terminated strings.
+pub(crate) fn write_arguments(args: &[&[u8]], variables: &Variables) -> Result<Arguments, &'static str> {
+    let variable_count = variables.iter().count();
+    let pointers_size = (args.len() + 1 + variable_count + 1) * 4;
+    let strings_size: usize = args.iter().map(|arg| arg.len() + 1).sum::<usize>()
+        + variables
+            .iter()
+            .map(|(name, value)| name.len() + 1 + value.len() + 1)
+            .sum::<usize>();
+
+    if pointers_size + strings_size > ARGUMENTS_SIZE {
+        return Err("Arguments and variables too long.");
+    }
+
+    let bytes = unsafe { slice::from_raw_parts_mut(ARGUMENTS as *mut u8, ARGUMENTS_SIZE) };
+
+    let mut pointer = 0;
+    let mut position = pointers_size;
+
+    let mut write_pointer = |bytes: &mut [u8], address: usize| {
+        bytes[pointer..pointer + 4].copy_from_slice(&(address as u32).to_le_bytes());
+        pointer += 4;
+    };
+
+    for arg in args {
+        write_pointer(bytes, ARGUMENTS + position);
+        bytes[position..position + arg.len()].copy_from_slice(arg);
+        bytes[position + arg.len()] = 0;
+        position += arg.len() + 1;
+    }
+
+    write_pointer(bytes, 0);
+
+    for (name, value) in variables.iter() {
+        write_pointer(bytes, ARGUMENTS + position);
+        bytes[position..position + name.len()].copy_from_slice(name);
+        position += name.len();
+        bytes[position] = b'=';
+        position += 1;
+        bytes[position..position + value.len()].copy_from_slice(value);
+        bytes[position + value.len()] = 0;
+        position += value.len() + 1;
+    }
+
+    write_pointer(bytes, 0);
+
+    Ok(Arguments {
+        argc: args.len() as u32,
+        argv: ARGUMENTS as u32,
+        envp: (ARGUMENTS + (args.len() + 1) * 4) as u32,
+    })
+}
+
+fn read_segment(program_header: &[u8], file_size: usize) -> Result<Segment, &'static str> {
+    let segment = Segment {
+        file_offset: read_u32(program_header, 0x04) as usize,
+        virtual_address: read_u32(program_header, 0x08) as usize,
+        file_size: read_u32(program_header, 0x10) as usize,
+        memory_size: read_u32(program_header, 0x14) as usize,
+        writable: read_u32(program_header, 0x18) & PROGRAM_FLAGS_WRITE != 0,
+        executable: read_u32(program_header, 0x18) & PROGRAM_FLAGS_EXECUTE != 0,
+    };
+
+    let file_end = segment
+        .file_offset
+        .checked_add(segment.file_size)
+        .ok_or("Segment size overflow error.")?;
+
+    if file_end > file_size {
+        return Err("Segment outside the file.");
+    }
+
+    if segment.memory_size < segment.file_size {
+        return Err("Segment smaller in memory than in the file.");
+    }
+
+    if segment.virtual_address.checked_add(segment.memory_size).is_none() {
+        return Err("Segment size overflow error.");
+    }
+
+    Ok(segment)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
     u16::from_le_bytes([
-        bytes[0],
-        bytes[1],
+        bytes[offset],
+        bytes[offset + 1],
     ])
 }
 
-fn read_u32(bytes: &[u8]) -> u32 {
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
     u32::from_le_bytes([
-        bytes[0],
-        bytes[1],
-        bytes[2],
-        bytes[3],
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
     ])
-}
\ No newline at end of file
+}
@@ -1,11 +1,25 @@
-use core::mem::MaybeUninit;
+use core::{mem::MaybeUninit, slice};
 
 use crate::{
-    bios_interface::{flash_page_erase, flash_write, get_char},
+    bios_interface::{flash_page_erase, flash_write, get_char_timeout, CTRL_C},
+    commands::CommandError,
     put,
 };
 
 const FS_PREFIX: &[u8] = b"[\x1b[1;34mfs\x1b[0m]";
+const BLOCK_SIZE: usize = 1024;
+
+// How long the input may pause before a paste is considered idle
+const PASTE_IDLE_TIMEOUT: u32 = 200;
+
+// A paste is collected in the editor's text buffer, which is free meanwhile,
+// and only written once complete; the USART can only hold one received byte,
+// so anything sent while a flash page is being written would be lost
+const PASTE_BUFFER: *mut u8 = (0x20000000 + 1024) as *mut u8;
+const PASTE_BUFFER_SIZE: usize = 7 * 1024;
+
+// Blocks are assembled in the first page of RAM before being written to flash
+const WRITE_BUFFER: *mut u8 = 0x20000000 as *mut u8;
 
 #[repr(C, align(1024))]
 pub(crate) struct FileSystem {
@@ -39,6 +53,15 @@ struct Block {
 #[repr(transparent)]
 pub(crate) struct BlockId(u8);
 
+pub(crate) struct FileWriter<'a> {
+    file_system: &'a mut FileSystem,
+    first_block: BlockId,
+    current_block: BlockId,
+    content_start: usize,
+    cursor: usize,
+    finished: bool,
+}
+
 // Lives at 0x20000000 + 16 * 1024
 // Saves into 0x08000000 + 63 * 1024
 // Handles 64 blocks at 0x08000000 + 64 * 1024
@@ -74,18 +97,31 @@ impl FileSystem {
     }
 
     pub(crate) fn save_file_system(&self) {
+        self.write_file_system(false);
+    }
+
+    fn write_file_system(&self, quiet: bool) {
         let source_page = 16;
         let target_page = 63;
-        self.save(source_page, target_page);
+        self.save(source_page, target_page, quiet);
     }
 
     pub(crate) fn save_block(&self, block_id: BlockId) {
+        // Blocks get saved while files are streamed in, possibly in the middle
+        // of a transfer, so unlike the file system they're written silently.
         let source_page = 0;
         let target_page = block_id.0 + 64;
-        self.save(source_page, target_page);
+        flash_page_erase(target_page);
+        flash_write(source_page, target_page);
     }
 
-    fn save(&self, source_page: u8, target_page: u8) {
+    fn save(&self, source_page: u8, target_page: u8, quiet: bool) {
+        if quiet {
+            flash_page_erase(target_page);
+            flash_write(source_page, target_page);
+            return;
+        }
+
         put!(FS_PREFIX, "Erasing flash page", target_page as u32 as i32);
         flash_page_erase(target_page);
         put!(
@@ -120,55 +156,65 @@ impl FileSystem {
             content.len() as i32
         );
 
-        let free_block = self.free_blocks.pop()?;
-        self.first_blocks.push(free_block);
-        let file_name = &file_name[..256.min(file_name.len())];
+        let mut writer = self.create_writer(file_name)?;
+        writer.write(content)?;
 
-        if free_block.0 > 63 {
-            return None;
-        }
-        let block_info = &mut self.block_info[free_block.0 as usize];
+        Some(writer.finish())
+    }
+
+    /// Starts a new file whose content is streamed in with `FileWriter::write`.
+    ///
+    /// The file only shows up in `list_files` once `FileWriter::finish` is
+    /// called, so an existing file with the same name stays intact until then
+    /// and only gets replaced if the new one was written completely.
+    pub(crate) fn create_writer(&mut self, file_name: &[u8]) -> Option<FileWriter<'_>> {
+        let file_name = &file_name[..255.min(file_name.len())];
+
+        let first_block = self.allocate_first_block()?;
+        let block_info = &mut self.block_info[first_block.0 as usize % 64];
         block_info.file_name_size = file_name.len() as u8;
-        block_info.content_size = content.len() as u16;
-        block_info.next_block = free_block;
+        block_info.content_size = 0;
+        block_info.next_block = first_block;
 
-        let write_block = (0x20000000 + 0 * 1024) as *mut u8;
-        for index in 0..file_name.len() {
-            unsafe {
-                write_block
-                    .offset(index as isize)
-                    .write_volatile(file_name[index]);
-            }
-        }
-        let content_start = round_up(file_name.len(), 4);
-        if content_start + content.len() >= 1024 {
-            // TODO: write to next block
-            return None;
+        for (index, &byte) in file_name.iter().enumerate() {
+            unsafe { WRITE_BUFFER.add(index).write_volatile(byte) };
         }
-        for index in 0..content.len() {
-            unsafe {
-                write_block
-                    .offset((content_start + index) as isize)
-                    .write_volatile(content[index]);
-            }
-        }
-        self.save_file_system();
-        self.save_block(free_block);
 
-        Some(free_block)
+        Some(FileWriter {
+            file_system: self,
+            first_block,
+            current_block: first_block,
+            content_start: round_up(file_name.len(), 4),
+            cursor: round_up(file_name.len(), 4),
+            finished: false,
+        })
     }
 
     pub(crate) fn remove_file(&mut self, file: BlockId) {
-        put!(FS_PREFIX, "Deleting file with block", file.0 as i32);
-        let mut current_block = file;
+        self.delete_file(file, false);
+    }
+
+    fn delete_file(&mut self, file: BlockId, quiet: bool) {
+        if !quiet {
+            put!(FS_PREFIX, "Deleting file with block", file.0 as i32);
+        }
         self.first_blocks.remove(file);
+        self.free_chain(file, quiet);
+    }
 
-        loop {
-            put!(
-                FS_PREFIX,
-                "Reclaiming content block",
-                current_block.0 as i32
-            );
+    fn free_chain(&mut self, first_block: BlockId, quiet: bool) {
+        let mut current_block = first_block;
+
+        // A chain can never be longer than the number of blocks, even if the
+        // block info got corrupted into a loop.
+        for _ in 0..64 {
+            if !quiet {
+                put!(
+                    FS_PREFIX,
+                    "Reclaiming content block",
+                    current_block.0 as i32
+                );
+            }
             self.free_blocks.push(current_block);
 
             let block_info = self.block_info[current_block.0 as usize % 64];
@@ -181,27 +227,104 @@ impl FileSystem {
         }
     }
 
-    pub(crate) fn paste_file(&mut self, file_name: &[u8], file_size: usize) {
+    /// Picks the free block followed by the longest run of free blocks, so
+    /// that files stay contiguous in flash whenever possible.
+    fn allocate_first_block(&mut self) -> Option<BlockId> {
+        let mut best_block = None;
+        let mut best_run = 0;
+
+        for block in self.free_blocks.iter() {
+            let mut run = 1;
+            while block.0 as usize + run < 64
+                && self.free_blocks.contains(BlockId(block.0 + run as u8))
+            {
+                run += 1;
+            }
+
+            if run > best_run {
+                best_block = Some(block);
+                best_run = run;
+            }
+        }
+
+        self.free_blocks.take(best_block?)
+    }
+
+    /// Prefers the block right after `previous_block` so that the chain stays
+    /// contiguous, falling back to any free block.
+    fn allocate_next_block(&mut self, previous_block: BlockId) -> Option<BlockId> {
+        let next_block = if previous_block.0 < 63 {
+            self.free_blocks.take(BlockId(previous_block.0 + 1))
+        } else {
+            None
+        };
+
+        let next_block = next_block.or_else(|| self.free_blocks.pop())?;
+
+        let block_info = &mut self.block_info[next_block.0 as usize % 64];
+        block_info.file_name_size = 0;
+        block_info.content_size = 0;
+        block_info.next_block = next_block;
+
+        self.block_info[previous_block.0 as usize % 64].next_block = next_block;
+
+        Some(next_block)
+    }
+
+    /// Creates a file from the next `file_size` received bytes.
+    ///
+    /// Binary files can contain Ctrl-C bytes, so it only stops the paste when
+    /// typed before the first byte or after the input has paused.
+    pub(crate) fn paste_file(&mut self, file_name: &[u8], file_size: usize) -> Result<(), CommandError> {
         put!("Pasting", file_size as i32, "bytes into:", file_name);
 
-        let mut content = [0; 1024];
+        if file_size > PASTE_BUFFER_SIZE {
+            discard_paste(file_size);
+            put!("Too large to paste, at most", PASTE_BUFFER_SIZE as i32, "bytes.");
+            return Err(CommandError::Failed);
+        }
 
-        if file_size >= 1024 {
-            put!("File size too large, multiple blocks not yet supported.");
-            return;
+        let buffer = unsafe { slice::from_raw_parts_mut(PASTE_BUFFER, file_size) };
+
+        let mut received = 0;
+        let mut idle = true;
+
+        while received < file_size {
+            let byte = match get_char_timeout(PASTE_IDLE_TIMEOUT) {
+                Some(byte) => byte,
+                None => {
+                    idle = true;
+                    continue;
+                }
+            };
+
+            if idle && byte == CTRL_C {
+                return Err(CommandError::Terminated);
+            }
+
+            buffer[received] = byte;
+            received += 1;
+            idle = false;
         }
 
-        for index in 0..file_size {
-            content[index % 1024] = get_char();
+        let mut writer = match self.create_writer(file_name) {
+            Some(writer) => writer,
+            None => {
+                put!("No free blocks left.");
+                return Err(CommandError::Failed);
+            }
+        };
+
+        if writer.write(buffer).is_none() {
+            put!("File system full, file not created.");
+            return Err(CommandError::Failed);
         }
 
         put!("Done.");
 
-        if let Some(file) = self.file(file_name) {
-            put!("Removing existing file");
-            self.remove_file(file);
-        }
-        self.create_file(file_name, &content[0..file_size % 1024]);
+        writer.finish();
+
+        Ok(())
     }
 
     pub(crate) fn list_files(&self) -> impl Iterator<Item = BlockId> + '_ {
@@ -253,19 +376,59 @@ impl FileSystem {
         &block.bytes
     }
 
-    #[inline(never)]
-    pub(crate) fn read_file(&self, block_id: BlockId) -> &[u8] {
-        let bytes = self.bytes(block_id);
-        let block_info = self.block(block_id);
+    /// Returns the file's content one block at a time.
+    pub(crate) fn read_file(&self, file: BlockId) -> impl Iterator<Item = &[u8]> + '_ {
+        self.chain(file).enumerate().map(move |(index, block_id)| {
+            let bytes = self.bytes(block_id);
+            let block_info = self.block(block_id);
+
+            let start = if index == 0 {
+                round_up(block_info.file_name_size as usize, 4)
+            } else {
+                0
+            };
+            let end = (start + block_info.content_size as usize).min(BLOCK_SIZE);
+
+            &bytes[start..end]
+        })
+    }
+
+    /// Returns the whole content as a single slice if the file's blocks happen
+    /// to be consecutive in flash, which is needed to execute it in place.
+    pub(crate) fn contiguous_file(&self, file: BlockId) -> Option<&[u8]> {
+        let mut size = 0;
+        let mut last_chunk_full = true;
+        let chunks = self.chain(file).zip(self.read_file(file));
+
+        for (expected_block, (block_id, chunk)) in (file.0..).zip(chunks) {
+            if block_id.0 != expected_block || !last_chunk_full {
+                return None;
+            }
 
-        let start = round_up(block_info.file_name_size as usize % 1024, 4);
-        let end = (start + block_info.content_size as usize) % 1024;
+            size += chunk.len();
+            last_chunk_full = chunk.as_ptr() as usize + chunk.len()
+                == self.bytes(block_id).as_ptr() as usize + BLOCK_SIZE;
+        }
+
+        let start = self.file_address(file) as *const u8;
+        Some(unsafe { core::slice::from_raw_parts(start, size) })
+    }
 
-        let end = if start > end { start } else { end };
+    pub(crate) fn file_size(&self, file: BlockId) -> usize {
+        self.read_file(file).map(|chunk| chunk.len()).sum()
+    }
 
-        let content = &bytes[start..end];
+    fn chain(&self, first_block: BlockId) -> impl Iterator<Item = BlockId> + '_ {
+        core::iter::successors(Some(first_block), move |&block_id| {
+            let next_block = self.block(block_id).next_block;
 
-        content
+            if next_block.0 == block_id.0 {
+                None
+            } else {
+                Some(next_block)
+            }
+        })
+        .take(64)
     }
 
     #[inline(never)]
@@ -295,6 +458,97 @@ impl FileSystem {
     }
 }
 
+impl FileWriter<'_> {
+    pub(crate) fn write(&mut self, content: &[u8]) -> Option<()> {
+        for &byte in content {
+            if self.cursor == BLOCK_SIZE {
+                self.current_block = self.file_system.allocate_next_block(self.current_block)?;
+                self.content_start = 0;
+                self.cursor = 0;
+            }
+
+            unsafe { WRITE_BUFFER.add(self.cursor).write_volatile(byte) };
+            self.cursor += 1;
+
+            if self.cursor == BLOCK_SIZE {
+                self.flush();
+            }
+        }
+
+        Some(())
+    }
+
+    /// Writes the content of another file, e.g. to extend an existing one.
+    pub(crate) fn copy_file(&mut self, file: BlockId) -> Option<()> {
+        for index in 0.. {
+            let mut offset = 0;
+
+            loop {
+                // Copied out in pieces since writing needs the file system too
+                let mut piece = [0; 64];
+                let length = match self.file_system.read_file(file).nth(index) {
+                    Some(chunk) if offset < chunk.len() => {
+                        let length = (chunk.len() - offset).min(piece.len());
+                        piece[..length].copy_from_slice(&chunk[offset..offset + length]);
+                        length
+                    }
+                    Some(_) => break,
+                    None => return Some(()),
+                };
+
+                self.write(&piece[..length])?;
+                offset += length;
+            }
+        }
+
+        Some(())
+    }
+
+    /// Saves the last block and adds the file to the file system, replacing
+    /// any existing file with the same name.
+    pub(crate) fn finish(self) -> BlockId {
+        self.complete(false)
+    }
+
+    /// Like `finish`, but without the file system's messages, for when the
+    /// other end of a transfer is waiting for the next packet.
+    pub(crate) fn finish_quietly(self) -> BlockId {
+        self.complete(true)
+    }
+
+    fn complete(mut self, quiet: bool) -> BlockId {
+        if self.cursor != BLOCK_SIZE {
+            self.flush();
+        }
+
+        let file_name = self.file_system.file_name(self.first_block);
+        if let Some(old_file) = self.file_system.file(file_name) {
+            self.file_system.delete_file(old_file, quiet);
+        }
+
+        self.file_system.first_blocks.push(self.first_block);
+        self.file_system.write_file_system(quiet);
+        self.finished = true;
+
+        self.first_block
+    }
+
+    fn flush(&mut self) {
+        let block_info = &mut self.file_system.block_info[self.current_block.0 as usize % 64];
+        block_info.content_size = (self.cursor - self.content_start) as u16;
+
+        self.file_system.save_block(self.current_block);
+    }
+}
+
+impl Drop for FileWriter<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.file_system.free_chain(self.first_block, false);
+        }
+    }
+}
+
 impl Stack {
     fn pop(&mut self) -> Option<BlockId> {
         if self.count == 0 || self.count > 64 {
@@ -314,8 +568,23 @@ impl Stack {
         self.count += 1;
     }
 
+    fn contains(&self, block_id: BlockId) -> bool {
+        self.iter().any(|element| element.0 == block_id.0)
+    }
+
+    fn take(&mut self, block_id: BlockId) -> Option<BlockId> {
+        if self.contains(block_id) {
+            self.remove(block_id);
+            Some(block_id)
+        } else {
+            None
+        }
+    }
+
     fn remove(&mut self, block_id: BlockId) {
-        for (index, block_id_element) in self.elements.iter().enumerate() {
+        let count = self.count as usize;
+
+        for (index, block_id_element) in self.elements.iter().take(count).enumerate() {
             if block_id.0 == block_id_element.0 {
                 self.count -= 1;
                 self.elements[index % 64] = self.elements[self.count as usize % 64];
@@ -326,13 +595,22 @@ impl Stack {
 
     fn iter(&self) -> impl Iterator<Item = BlockId> + '_ {
         (0..self.count)
-            .into_iter()
             .map(|index| self.elements[index as usize % 64])
     }
 }
 
+/// Reads and drops what is left of a paste that failed, so that the shell
+/// doesn't run it as commands. Stops early if the input pauses.
+fn discard_paste(remaining: usize) {
+    for _ in 0..remaining {
+        if get_char_timeout(PASTE_IDLE_TIMEOUT).is_none() {
+            break;
+        }
+    }
+}
+
 fn round_up(value: usize, multiple: usize) -> usize {
-    if value % multiple == 0 {
+    if value.is_multiple_of(multiple) {
         value
     } else {
         value / multiple * multiple + multiple
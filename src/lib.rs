@@ -1,23 +1,38 @@
-#![no_std]
-#![no_main]
+// Tests run on the host, with std
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
+use commands::{find_command, CommandError, Shell};
 use elf::read_elf;
 use file_system::FileSystem;
-use xmodem::receive_file;
+use tokenizer::{Tokens, MAX_ARGS};
+use variables::Variables;
+
+const AUTOEXEC_FILE: &[u8] = b"autoexec";
 
 use syslib::{put, putn};
 
 use crate::{
-    bios_interface::{ecall1, get_char},
+    bios_interface::{get_char, get_char_timeout},
     edit_line::{EditLine, EditLineEvent},
 };
 
 mod bios_interface;
+mod commands;
+mod completion;
 mod edit_line;
+mod editor;
 mod file_system;
+mod memory;
+#[cfg(not(test))]
 mod panic;
+mod redirect;
+mod script;
 mod xmodem;
 mod sys_call;
+mod tokenizer;
+mod trap;
+mod variables;
 mod elf;
 
 #[no_mangle]
@@ -26,11 +41,27 @@ fn os_main() {
 
     put!("Number from Rust:", 1234);
 
-    put_prompt();
-
     let mut edit_line = EditLine::new();
     let mut file_system = FileSystem::load_from_flash();
 
+    let mut variables = Variables::new();
+
+    edit_line.load_history(file_system);
+    variables.load(file_system);
+
+    if file_system.file(AUTOEXEC_FILE).is_some() {
+        let mut shell = Shell {
+            file_system: &mut file_system,
+            edit_line: &mut edit_line,
+            variables: &mut variables,
+            script_depth: 0,
+        };
+
+        run_autoexec(&mut shell);
+    }
+
+    put_prompt();
+
     loop {
         let input_character = get_char();
 
@@ -39,11 +70,34 @@ fn os_main() {
             Some(EditLineEvent::EscapeCode(escape_code)) => {
                 process_escape_code(escape_code);
                 put_prompt();
+                edit_line.redraw();
             }
             Some(EditLineEvent::Command(command)) => {
-                process_command(command, &mut file_system);
+                // Copied out of the edit line, so that commands can use it too
+                let mut buffer = [0; 256];
+                let command = {
+                    buffer[..command.len()].copy_from_slice(command);
+                    &buffer[..command.len()]
+                };
+
+                let mut shell = Shell {
+                    file_system: &mut file_system,
+                    edit_line: &mut edit_line,
+                    variables: &mut variables,
+                    script_depth: 0,
+                };
+
+                let _ = process_command(command, &mut shell);
                 put_prompt();
             }
+            Some(EditLineEvent::Complete { repeated }) => {
+                completion::complete(&mut edit_line, file_system, repeated);
+            }
+            Some(EditLineEvent::ClearScreen) => {
+                putn!("\x1b[2J\x1b[H");
+                put_prompt();
+                edit_line.redraw();
+            }
             Some(EditLineEvent::UnrecognizedCode(c)) => {
                 put!();
                 put!("Unrecognized ascii code:", c as u32 as i32);
@@ -53,209 +107,254 @@ fn os_main() {
     }
 }
 
+/// Runs the autoexec script, unless a key is held down while booting.
+fn run_autoexec(shell: &mut Shell) {
+    put!("Running", AUTOEXEC_FILE, "(hold any key to skip)...");
+
+    if get_char_timeout(500).is_some() {
+        // Throw away the rest of the held key's repeats
+        while get_char_timeout(100).is_some() {}
+        put!("Skipped", AUTOEXEC_FILE);
+        return;
+    }
+
+    let _ = script::run_script(shell, AUTOEXEC_FILE);
+}
+
 fn put_prompt() {
     putn!("\x1b[1;34m>\x1b[0m ");
 }
 
 fn process_escape_code(escape_code: &[u8]) {
     put!();
-    match escape_code {
-        b"[D" => put!("Escape code: Left key"),
-        b"[C" => put!("Escape code: Right key"),
-        b"[A" => put!("Escape code: Up key"),
-        b"[B" => put!("Escape code: Down key"),
-        _ => {
-            putn!("Unrecognized escape code: Esc");
-
-            for &byte in escape_code {
-                if byte.is_ascii_graphic() {
-                    putn!(" '", byte, "'");
-                } else {
-                    putn!(" ", byte as u32 as i32);
-                }
-            }
+    putn!("Unrecognized escape code: Esc");
 
-            put!();
+    for &byte in escape_code {
+        if byte.is_ascii_graphic() {
+            putn!(" '", byte, "'");
+        } else {
+            putn!(" ", byte as u32 as i32);
         }
     }
+
+    put!();
 }
 
+/// Runs one command line, returning an error if it could not be run or the
+/// command failed.
 #[inline(never)]
-fn process_command(command: &[u8], file_system: &mut &mut FileSystem) {
-    if command.is_empty() {
-        return;
-    }
+fn process_command(command: &[u8], shell: &mut Shell) -> Result<(), CommandError> {
+    let result = run_command(command, shell);
+
+    shell.variables.status = match result {
+        Ok(()) => 0,
+        Err(CommandError::Terminated) => 130,
+        Err(CommandError::Exited(exit_code)) => exit_code,
+        Err(_) => 1,
+    };
 
-    let (command, args) = get_word(command);
+    result
+}
 
-    match command {
-        b"help" => {
-            put!("No help available at this time.");
-        }
-        b"ecall" => {
-            put!("Calling system interrupt...");
-            let (arg1, _) = get_word(args);
-            let arg1 = string_to_number(arg1);
-            ecall1(1, arg1);
-            put!("Back to Rust now.");
-        }
-        b"leds" => {
-            let (arg1, _) = get_word(args);
-            sys_call::set_leds(arg1);
-        }
-        b"delay" => {
-            let (arg1, _) = get_word(args);
-            let milliseconds = string_to_number(arg1);
-            sys_call::delay(milliseconds);
-        }
-        b"run" => {
-            let (arg1, _) = get_word(args);
-            run_program(file_system, arg1)
-        }
-        b"exec" => {
-            let (arg1, _) = get_word(args);
-            let address = string_to_number(arg1);
-            sys_call::exec(address);
-        }
-        b"exit" => {
-            let (arg1, _) = get_word(args);
-            let exit_code = string_to_number(arg1);
-            sys_call::exit(exit_code);
+fn run_command(command: &[u8], shell: &mut Shell) -> Result<(), CommandError> {
+    let tokens = match Tokens::parse(command, shell.variables) {
+        Ok(tokens) => tokens,
+        Err(err) => {
+            put!("Error:", err);
+            return Err(CommandError::Failed);
         }
-        b"fs" => match args {
-            b"stats" => {
-                file_system.print_stats();
-            }
-            b"save" => {
-                file_system.save_file_system();
-            }
-            b"load" => {
-                *file_system = FileSystem::load_from_flash();
-            }
-            b"reset" => {
-                *file_system = FileSystem::new_from_scratch();
-            }
-            _ => {
-                put!("Unknown argument:", args);
-                put!("Subcommands: stats, save, load, reset");
-            }
-        },
-        b"write" | b"create" => {
-            let (file_name, content) = get_word(args);
+    };
 
-            if let None = file_system.create_file(file_name, content) {
-                put!("No file created.");
-            }
-        }
-        b"ls" => {
-            let (file_name_arg, _) = get_word(args);
-
-            for file in file_system.list_files() {
-                let file_name = file_system.file_name(file);
-                if file_name_arg.is_empty() || file_name == file_name_arg {
-                    put!(file_name);
-                }
-            }
-        }
-        b"cat" | b"read" => {
-            let (file_name, _) = get_word(args);
+    let mut argv = [b"" as &[u8]; MAX_ARGS];
+    let (name, args) = match tokens.words(&mut argv) {
+        [name, args @ ..] => (*name, args),
+        [] => return Ok(()),
+    };
 
-            if let Some(file) = file_system.file(file_name) {
-                put!(file_system.read_file(file));
-            } else {
-                put!("File not found:", file_name);
-            }
+    let command = match find_command(name) {
+        Some(command) => command,
+        None => {
+            put!("Unknown command:", name);
+            return Err(CommandError::Failed);
         }
-        b"rm" => {
-            let (file_name, _) = get_word(args);
+    };
 
-            let file = file_system
-                .list_files()
-                .find(|&file| file_system.file_name(file) == file_name);
+    if args.len() < command.min_args || args.len() > command.max_args {
+        put!("Usage:", command.usage);
+        return Err(CommandError::Usage);
+    }
 
-            if let Some(file) = file {
-                file_system.remove_file(file);
-            } else {
-                put!("File not found:", file_name);
-            }
+    let result = match tokens.redirect() {
+        Some((file_name, redirect)) => {
+            redirect::run_redirected(shell, file_name, redirect, |shell| (command.handler)(shell, args))
         }
-        b"rx" => {
-            receive_file(file_system);
-        }
-        b"paste" => {
-            let (file_name, rest) = get_word(args);
-            let (file_size, _) = get_word(rest);
-            let file_size = string_to_number(file_size) as usize;
+        None => (command.handler)(shell, args),
+    };
 
-            file_system.paste_file(file_name, file_size);
-        }
-        c => put!("Unknown command:", c),
+    match result {
+        Err(CommandError::Usage) => put!("Usage:", command.usage),
+        Err(CommandError::Terminated) => put!("Terminated."),
+        _ => (),
     }
-}
 
-fn get_word(s: &[u8]) -> (&[u8], &[u8]) {
-    if s.is_empty() {
-        return (s, b"");
-    }
+    result
+}
 
-    for (index, &byte) in s.iter().enumerate() {
-        if byte.is_ascii_whitespace() {
-            let (word, rest) = s.split_at(index % s.len());
+/// Parses a decimal, `0x` hexadecimal or `0b` binary number, optionally
+/// negative.
+///
+/// Negative numbers are returned in two's complement, so anything from
+/// `i32::MIN` to `u32::MAX` fits.
+fn parse_number(s: &[u8]) -> Result<u32, &'static str> {
+    let (negative, s) = match s {
+        [b'-', rest @ ..] => (true, rest),
+        _ => (false, s),
+    };
 
-            if rest.is_empty() {
-                return (word, b"");
-            }
+    let (radix, digits) = match s {
+        [b'0', b'x' | b'X', rest @ ..] => (16, rest),
+        [b'0', b'b' | b'B', rest @ ..] => (2, rest),
+        _ => (10, s),
+    };
 
-            return (word, &rest[1..]);
-        }
+    if digits.is_empty() {
+        return Err("Expected digits.");
     }
 
-    return (s, b"");
-}
+    let mut number: u32 = 0;
 
-fn string_to_number(s: &[u8]) -> u32 {
-    let mut number = 0;
+    for &byte in digits {
+        let digit = match (byte as char).to_digit(radix) {
+            Some(digit) => digit,
+            None => return Err("Invalid digit."),
+        };
 
-    for &byte in s {
-        if byte >= b'0' && byte <= b'9' {
-            let digit = byte - b'0';
+        number = number
+            .checked_mul(radix)
+            .and_then(|number| number.checked_add(digit))
+            .ok_or("Number too large.")?;
+    }
 
-            number *= 10;
-            number += digit as u32;
+    if negative {
+        if number > i32::MIN.unsigned_abs() {
+            return Err("Number too small.");
         }
+
+        number = number.wrapping_neg();
     }
 
-    number
+    Ok(number)
 }
 
-fn run_program(file_system: &mut FileSystem, file_name: &[u8]) {
+/// Runs the program in the file named by `args[0]`, passing all of `args` to
+/// it as `argc`/`argv` and the shell variables as `envp`.
+fn run_program(file_system: &mut FileSystem, variables: &Variables, args: &[&[u8]]) -> Result<(), CommandError> {
+    let file_name = args[0];
+
     let block_id = match file_system.file(file_name) {
         Some(block_id) => block_id,
         None => {
             put!("File not found:", file_name);
-            return;
-        },
+            return Err(CommandError::Failed);
+        }
     };
 
-    let contents = file_system.read_file(block_id);
+    let contents = match file_system.contiguous_file(block_id) {
+        Some(contents) => contents,
+        None => {
+            put!("Cannot run program: File is fragmented.");
+            return Err(CommandError::Failed);
+        }
+    };
 
-    if contents.len() < 56 {
-        put!("Cannot run program: Header too short.");
-        return;
-    }
+    let file_address = file_system.file_address(block_id);
 
-    let entry_point = match read_elf(contents) {
+    let exec_address = match read_elf(contents).and_then(|elf| {
+        let layout = elf::load(&elf, file_address)?;
+        layout
+            .runtime_address(elf.entry_point)
+            .ok_or("Entry point outside the program.")
+    }) {
         Ok(address) => address,
         Err(err) => {
             put!("Cannot run program:", err);
-            return;
+            return Err(CommandError::Failed);
         }
     };
 
-    let file_address = file_system.file_address(block_id);
-    let exec_address = file_address + entry_point;
+    let arguments = match elf::write_arguments(args, variables) {
+        Ok(arguments) => arguments,
+        Err(err) => {
+            put!("Cannot run program:", err);
+            return Err(CommandError::Failed);
+        }
+    };
 
     put!("Running program from:", file_address as i32, exec_address as i32);
 
-    sys_call::exec(exec_address as u32);
+    let (exit_code, terminated) = sys_call::exec(
+        exec_address as u32,
+        arguments.argc,
+        arguments.argv,
+        arguments.envp,
+    );
+
+    exit_status(exit_code, terminated)
+}
+
+/// Turns what `exec` returned into the result of the command that ran the
+/// program, so that a non-zero exit code shows up in `$?` and stops scripts.
+fn exit_status(exit_code: u32, terminated: bool) -> Result<(), CommandError> {
+    if terminated {
+        return Err(CommandError::Terminated);
+    }
+
+    if exit_code != 0 {
+        put!("Program exited with code", exit_code as i32);
+        return Err(CommandError::Exited(exit_code as i32));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_decimal_hex_and_binary() {
+        assert_eq!(parse_number(b"0"), Ok(0));
+        assert_eq!(parse_number(b"1234"), Ok(1234));
+        assert_eq!(parse_number(b"0x20000400"), Ok(0x2000_0400));
+        assert_eq!(parse_number(b"0XfF"), Ok(0xFF));
+        assert_eq!(parse_number(b"0b101"), Ok(5));
+        assert_eq!(parse_number(b"0B11"), Ok(3));
+    }
+
+    #[test]
+    fn negative_numbers_are_twos_complement() {
+        assert_eq!(parse_number(b"-1"), Ok(u32::MAX));
+        assert_eq!(parse_number(b"-0x10"), Ok(-16i32 as u32));
+        assert_eq!(parse_number(b"-2147483648"), Ok(i32::MIN as u32));
+        assert_eq!(parse_number(b"-2147483649"), Err("Number too small."));
+    }
+
+    #[test]
+    fn rejects_overflow() {
+        assert_eq!(parse_number(b"4294967295"), Ok(u32::MAX));
+        assert_eq!(parse_number(b"4294967296"), Err("Number too large."));
+        assert_eq!(parse_number(b"0x100000000"), Err("Number too large."));
+        assert_eq!(parse_number(&[b'1'; 40]), Err("Number too large."));
+    }
+
+    #[test]
+    fn rejects_bad_digits() {
+        assert_eq!(parse_number(b""), Err("Expected digits."));
+        assert_eq!(parse_number(b"-"), Err("Expected digits."));
+        assert_eq!(parse_number(b"0x"), Err("Expected digits."));
+        assert_eq!(parse_number(b"12a"), Err("Invalid digit."));
+        assert_eq!(parse_number(b"0b102"), Err("Invalid digit."));
+        assert_eq!(parse_number(b"--1"), Err("Invalid digit."));
+        assert_eq!(parse_number(b" 1"), Err("Invalid digit."));
+    }
 }
@@ -0,0 +1,135 @@
+use crate::{put, putn};
+
+struct Region {
+    start: u32,
+    end: u32,
+    /// Flash is programmed through its controller, stores to it fault.
+    writable: bool,
+}
+
+const fn region(start: u32, end: u32, writable: bool) -> Region {
+    Region {
+        start,
+        end,
+        writable,
+    }
+}
+
+/// Address ranges that can be accessed without a bus fault.
+static REGIONS: &[Region] = &[
+    // Flash
+    region(0x0800_0000, 0x0802_0000, false),
+    // Boot loader and option bytes
+    region(0x1FFF_B000, 0x1FFF_F810, false),
+    // RAM
+    region(0x2000_0000, 0x2000_8000, true),
+    // APB1, APB2 and AHB1 peripherals
+    region(0x4000_0000, 0x4000_7800, true),
+    region(0x4001_0000, 0x4001_5800, true),
+    region(0x4001_8000, 0x4002_4000, true),
+    // USB
+    region(0x5000_0000, 0x5004_0000, true),
+    // Core timer and interrupt controller
+    region(0xD100_0000, 0xD100_1000, true),
+    region(0xD200_0000, 0xD201_0000, true),
+];
+
+/// Checks that `length` bytes at `address` are all inside one mapped region,
+/// writable if `write` is set, and that `address` is aligned to `alignment`.
+pub(crate) fn check_access(
+    address: u32,
+    length: u32,
+    alignment: u32,
+    write: bool,
+) -> Result<(), &'static str> {
+    if !address.is_multiple_of(alignment) {
+        return Err("Address is not aligned.");
+    }
+
+    let end = address.checked_add(length).ok_or("Address range wraps around.")?;
+
+    let region = REGIONS
+        .iter()
+        .find(|region| region.start <= address && end <= region.end)
+        .ok_or("Address range is not mapped.")?;
+
+    if write && !region.writable {
+        return Err("Address range is read-only.");
+    }
+
+    Ok(())
+}
+
+/// Prints `bytes` as hex and ASCII, 16 per line, labelled starting from `address`.
+pub(crate) fn dump(address: u32, bytes: impl Iterator<Item = u8>) {
+    let mut line = [0; 16];
+    let mut length = 0;
+    let mut line_address = address;
+
+    for byte in bytes {
+        line[length] = byte;
+        length += 1;
+
+        if length == line.len() {
+            dump_line(line_address, &line[..length]);
+            line_address = line_address.wrapping_add(length as u32);
+            length = 0;
+        }
+    }
+
+    if length > 0 {
+        dump_line(line_address, &line[..length]);
+    }
+}
+
+fn dump_line(address: u32, bytes: &[u8]) {
+    put_hex(address, 8);
+    putn!(": ");
+
+    for index in 0..16 {
+        match bytes.get(index) {
+            Some(&byte) => put_hex(byte as u32, 2),
+            None => putn!("  "),
+        }
+        putn!(if index == 7 { "  " } else { " " });
+    }
+
+    putn!("|");
+    for &byte in bytes {
+        let printable = byte.is_ascii_graphic() || byte == b' ';
+        putn!(if printable { byte } else { b'.' });
+    }
+    put!("|");
+}
+
+/// Prints the lowest `digits` hex digits of `value`.
+pub(crate) fn put_hex(value: u32, digits: u32) {
+    for digit in (0..digits).rev() {
+        let nibble = (value >> (digit * 4)) & 0xF;
+        putn!(b"0123456789abcdef"[nibble as usize]);
+    }
+}
+
+/// Reads `length` bytes starting at `address`, a word at a time since some
+/// peripheral registers cannot be read byte by byte.
+///
+/// Both must be multiples of 4, and the range checked with `check_access`.
+pub(crate) fn read_words(address: u32, length: u32) -> impl Iterator<Item = u8> {
+    (address..address + length).step_by(4).flat_map(|word_address| {
+        let word = unsafe { (word_address as *const u32).read_volatile() };
+        word.to_le_bytes()
+    })
+}
+
+/// Writes `value` with a store of `width` bits, which must be 8, 16 or 32.
+///
+/// The address must have been checked with `check_access`.
+pub(crate) fn write(address: u32, value: u32, width: u32) {
+    unsafe {
+        match width {
+            8 => (address as *mut u8).write_volatile(value as u8),
+            16 => (address as *mut u16).write_volatile(value as u16),
+            _ => (address as *mut u32).write_volatile(value),
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use core::slice;
+
+use syslib::print::{is_redirected, with_output};
+
+use crate::{
+    commands::{CommandError, Shell},
+    put,
+    tokenizer::Redirect,
+};
+
+// Output is collected in RAM and only written once the command is done, so
+// that the command can still use the file system (and its write buffer)
+const CAPTURE_BUFFER: *mut u8 = (0x20000000 + 8 * 1024) as *mut u8;
+const CAPTURE_SIZE: usize = 8 * 1024;
+
+/// Runs a command with its output going into a file instead of the terminal.
+pub(crate) fn run_redirected(
+    shell: &mut Shell,
+    file_name: &[u8],
+    redirect: Redirect,
+    run: impl FnOnce(&mut Shell) -> Result<(), CommandError>,
+) -> Result<(), CommandError> {
+    if is_redirected() {
+        put!("Output is already redirected.");
+        return Err(CommandError::Failed);
+    }
+
+    let buffer = unsafe { slice::from_raw_parts_mut(CAPTURE_BUFFER, CAPTURE_SIZE) };
+    let mut length = 0;
+    let mut truncated = false;
+
+    let result = with_output(
+        &mut |byte| {
+            if length < buffer.len() {
+                buffer[length] = byte;
+                length += 1;
+            } else {
+                truncated = true;
+            }
+        },
+        || run(shell),
+    );
+
+    let old_file = shell.file_system.file(file_name);
+
+    let mut writer = match shell.file_system.create_writer(file_name) {
+        Some(writer) => writer,
+        None => {
+            put!("No free blocks left.");
+            return Err(CommandError::Failed);
+        }
+    };
+
+    if let (Redirect::Append, Some(old_file)) = (redirect, old_file) {
+        if writer.copy_file(old_file).is_none() {
+            put!("File system full, output not saved.");
+            return Err(CommandError::Failed);
+        }
+    }
+
+    if writer.write(&buffer[..length]).is_none() {
+        put!("File system full, output not saved.");
+        return Err(CommandError::Failed);
+    }
+
+    writer.finish();
+
+    if truncated {
+        put!("Output too long, only the first", CAPTURE_SIZE as i32, "bytes were saved.");
+        return Err(CommandError::Failed);
+    }
+
+    result
+}
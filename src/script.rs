@@ -0,0 +1,99 @@
+use crate::{
+    commands::{CommandError, Shell},
+    file_system::{BlockId, FileSystem},
+    process_command, put,
+};
+
+/// Scripts can source other scripts, but each level uses up stack.
+const MAX_SCRIPT_DEPTH: usize = 4;
+
+/// Runs each line of a file as a command, stopping at the first one that
+/// fails. Empty lines and lines starting with `#` are skipped.
+pub(crate) fn run_script(shell: &mut Shell, file_name: &[u8]) -> Result<(), CommandError> {
+    if shell.script_depth == MAX_SCRIPT_DEPTH {
+        put!("Scripts nested too deeply.");
+        return Err(CommandError::Failed);
+    }
+
+    let mut position = 0;
+    let mut line_number: i32 = 0;
+
+    loop {
+        // Looked up again for every line, since commands may change the file
+        let file = match shell.file_system.file(file_name) {
+            Some(file) => file,
+            None if line_number == 0 => {
+                put!("File not found:", file_name);
+                return Err(CommandError::Failed);
+            }
+            None => {
+                put!("Script removed while running:", file_name);
+                return Err(CommandError::Failed);
+            }
+        };
+
+        let mut line = [0; 256];
+        let length = match read_line(shell.file_system, file, &mut position, &mut line) {
+            Some(length) => length,
+            None => return Ok(()),
+        };
+        line_number += 1;
+
+        let command = trim(&line[..length]);
+
+        if command.is_empty() || command[0] == b'#' {
+            continue;
+        }
+
+        shell.script_depth += 1;
+        let result = process_command(command, shell);
+        shell.script_depth -= 1;
+
+        if result.is_err() {
+            put!("Script stopped at line", line_number, "of", file_name);
+            return Err(CommandError::Failed);
+        }
+    }
+}
+
+/// Copies the line starting at `position` into `line` and moves `position`
+/// past it. Lines longer than `line` are cut short.
+fn read_line(
+    file_system: &FileSystem,
+    file: BlockId,
+    position: &mut usize,
+    line: &mut [u8; 256],
+) -> Option<usize> {
+    let mut bytes = file_system.read_file(file).flatten().skip(*position).peekable();
+
+    bytes.peek()?;
+
+    let mut length = 0;
+
+    for &byte in bytes {
+        *position += 1;
+
+        if byte == b'\n' {
+            break;
+        }
+
+        if length < line.len() {
+            line[length] = byte;
+            length += 1;
+        }
+    }
+
+    Some(length)
+}
+
+fn trim(mut line: &[u8]) -> &[u8] {
+    while let [b' ' | b'\t', rest @ ..] = line {
+        line = rest;
+    }
+
+    while let [rest @ .., b' ' | b'\t' | b'\r'] = line {
+        line = rest;
+    }
+
+    line
+}
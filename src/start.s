@@ -47,302 +47,94 @@ disable_interrupts:
 setup_interrupts:
     la      a0, interrupt_handler
     csrw    mtvec, a0
-    csrwi   mie, 1 << 3     # Enable software interrupts
+    li      a0, 1 << 3 | 1 << 7
+    csrw    mie, a0         # Enable software and timer interrupts
     ret
 
 interrupt_handler:
-    # Save basic registers
+    # Save all registers into a TrapFrame (see trap.rs) below the globals
+    # page; the Rust handler gets the stack below it
     csrw    mscratch, sp
-    li      sp, 0x20000000 + 31 * 1024
-    sw      ra, -4(sp)
-    sw      a0, -8(sp)
-    sw      a1, -12(sp)
-    sw      a2, -16(sp)
-    sw      a3, -20(sp)
-    sw      a4, -24(sp)
-    sw      a5, -28(sp)
-    sw      a6, -32(sp)
-    sw      t0, -36(sp)
-    sw      t1, -40(sp)
-    sw      s0, -44(sp)
-    sw      s1, -48(sp)
-
-    # Skip program counter over the ecall instruction
-    csrr    a0, mepc
-    addi    a0, a0, 4
-    csrw    mepc, a0
-
-    csrr    a2, mcause
-    addi    a2, a2, -11     # Environment call from M-mode
-    beqz    a2, 1f
-
-    # Not M-mode ecall; reject and return
-    la      a0, bios_prefix
-    li      a1, 16
-    call    usart_send_string
-    la      a0, unknown_interrupt_taken
-    li      a1, 26
-    call    usart_send_string
-    j       ecall_end
-
-1:  # Check system call number
-    lw      a0, -8(sp)
-    addi    a0, a0, -1
-    beqz    a0, syscall_delay
-    addi    a0, a0, -1
-    beqz    a0, syscall_leds
-    addi    a0, a0, -1
-    beqz    a0, syscall_exec
-    addi    a0, a0, -1
-    beqz    a0, syscall_exit
-    addi    a0, a0, -1
-    beqz    a0, syscall_put_byte
-
-ecall_end:
-    # Restore registers
-    lw      ra, -4(sp)
-    lw      a0, -8(sp)
-    lw      a1, -12(sp)
-    lw      a2, -16(sp)
-    lw      a3, -20(sp)
-    lw      a4, -24(sp)
-    lw      a5, -28(sp)
-    lw      a6, -32(sp)
-    lw      t0, -36(sp)
-    lw      t1, -40(sp)
-    lw      s0, -44(sp)
-    lw      s1, -48(sp)
-    csrr    sp, mscratch
+    li      sp, 0x20000000 + 31 * 1024 - 32 * 4
+
+    sw      x1, 1*4(sp)
+    sw      x3, 3*4(sp)
+    sw      x4, 4*4(sp)
+    sw      x5, 5*4(sp)
+    sw      x6, 6*4(sp)
+    sw      x7, 7*4(sp)
+    sw      x8, 8*4(sp)
+    sw      x9, 9*4(sp)
+    sw      x10, 10*4(sp)
+    sw      x11, 11*4(sp)
+    sw      x12, 12*4(sp)
+    sw      x13, 13*4(sp)
+    sw      x14, 14*4(sp)
+    sw      x15, 15*4(sp)
+    sw      x16, 16*4(sp)
+    sw      x17, 17*4(sp)
+    sw      x18, 18*4(sp)
+    sw      x19, 19*4(sp)
+    sw      x20, 20*4(sp)
+    sw      x21, 21*4(sp)
+    sw      x22, 22*4(sp)
+    sw      x23, 23*4(sp)
+    sw      x24, 24*4(sp)
+    sw      x25, 25*4(sp)
+    sw      x26, 26*4(sp)
+    sw      x27, 27*4(sp)
+    sw      x28, 28*4(sp)
+    sw      x29, 29*4(sp)
+    sw      x30, 30*4(sp)
+    sw      x31, 31*4(sp)
+    csrr    t0, mscratch
+    sw      t0, 2*4(sp)     # x2 (sp)
+    csrr    t0, mepc
+    sw      t0, 0*4(sp)     # Program counter, in place of x0
+
+    mv      a0, sp
+    call    trap_handler
+
+    # The handler may have changed any of them, e.g. to switch programs
+    lw      t0, 0*4(sp)
+    csrw    mepc, t0
+
+    lw      x1, 1*4(sp)
+    lw      x3, 3*4(sp)
+    lw      x4, 4*4(sp)
+    lw      x5, 5*4(sp)
+    lw      x6, 6*4(sp)
+    lw      x7, 7*4(sp)
+    lw      x8, 8*4(sp)
+    lw      x9, 9*4(sp)
+    lw      x10, 10*4(sp)
+    lw      x11, 11*4(sp)
+    lw      x12, 12*4(sp)
+    lw      x13, 13*4(sp)
+    lw      x14, 14*4(sp)
+    lw      x15, 15*4(sp)
+    lw      x16, 16*4(sp)
+    lw      x17, 17*4(sp)
+    lw      x18, 18*4(sp)
+    lw      x19, 19*4(sp)
+    lw      x20, 20*4(sp)
+    lw      x21, 21*4(sp)
+    lw      x22, 22*4(sp)
+    lw      x23, 23*4(sp)
+    lw      x24, 24*4(sp)
+    lw      x25, 25*4(sp)
+    lw      x26, 26*4(sp)
+    lw      x27, 27*4(sp)
+    lw      x28, 28*4(sp)
+    lw      x29, 29*4(sp)
+    lw      x30, 30*4(sp)
+    lw      x31, 31*4(sp)
+    lw      x2, 2*4(sp)     # Last, as it points to the frame
 
     mret
 
 # --------
 
-# System calls
-
-syscall_delay:
-    # Print "Syscall: Delay"
-    la      a0, bios_prefix
-    li      a1, 16
-    call    usart_send_string
-    la      a0, ascii_syscall_delay
-    li      a1, 16
-    call    usart_send_string
-
-    # Syscall arguments
-    lw      a2, -12(sp)     # Arg 1: Milliseconds
-
-    li      a0, 2000
-    mul     a2, a2, a0
-
-1:  addi    a2, a2, -1
-    bnez    a2, 1b
-
-    j       ecall_end
-
-
-syscall_leds:
-    # Print "Syscall: Set LEDs"
-    la      a0, bios_prefix
-    li      a1, 16
-    call    usart_send_string
-    la      a0, ascii_syscall_set_leds
-    li      a1, 19
-    call    usart_send_string
-
-    # Syscall arguments
-    lw      a2, -12(sp)     # Arg 1: Led mask
-
-    # Turn on red led
-    andi    a3, a2, 0b000001
-    beqz    a3, 2f
-    call    turn_on_red_led
-
-2:  # Turn on green led
-    andi    a3, a2, 0b000010
-    beqz    a3, 3f
-    call    turn_on_green_led
-
-3:  # Turn on blue led
-    andi    a3, a2, 0b000100
-    beqz    a3, 4f
-    call    turn_on_blue_led
-
-4:   # Turn off red led
-    andi    a3, a2, 0b001000
-    beqz    a3, 5f
-    call    turn_off_red_led
-
-5:  # Turn off green led
-    andi    a3, a2, 0b010000
-    beqz    a3, 6f
-    call    turn_off_green_led
-
-6:  # Turn off blue led
-    andi    a3, a2, 0b100000
-    beqz    a3, 7f
-    call    turn_off_blue_led
-
-7:  j       ecall_end
-
-
-syscall_exec:
-    # Print "Syscall: Exec"
-    la      a0, bios_prefix
-    li      a1, 16
-    call    usart_send_string
-    la      a0, ascii_syscall_exec
-    li      a1, 15
-    call    usart_send_string
-
-    li      a0, 0x20000000 + 31 * 1024  # Globals
-    addi    a1, a0, 0x04    # Execution stack location
-
-    lw      a2, 0x00(a0)    # Execution stack depth
-    add     a3, a1, a2      # Current save point
-
-    csrr    a4, mepc        # Current program counter
-    sw      a4, 0x00(a3)    # Save to save point
-
-    # Save registers
-    lw      a4, -4(sp)
-    sw      a4, 1*4(a3)       # x1 (ra)
-    csrr    a4, mscratch
-    sw      a4, 2*4(a3)    # x2 (sp)
-    sw      x3, 3*4(a3)    # x3 (gp)
-    sw      x4, 4*4(a3)    # x4 (tp)
-    lw      a4, -36(sp)
-    sw      a4, 5*4(a3)    # x5 (t0)
-    lw      a4, -40(sp)
-    sw      a4, 6*4(a3)    # x6 (t1)
-    sw      x7, 7*4(a3)    # x7 (t2)
-    lw      a4, -44(sp)
-    sw      a4, 8*4(a3)    # x8 (s0/fp)
-    lw      a4, -48(sp)
-    sw      a4, 9*4(a3)    # x9 (s1)
-    lw      a4, -8(sp)
-    sw      a4, 10*4(a3)    # x10 (a0)
-    lw      a4, -12(sp)
-    sw      a4, 11*4(a3)    # x11 (a1)
-    lw      a4, -16(sp)
-    sw      a4, 12*4(a3)    # x12 (a2)
-    lw      a4, -20(sp)
-    sw      a4, 13*4(a3)    # x13 (a3)
-    lw      a4, -24(sp)
-    sw      a4, 14*4(a3)    # x14 (a4)
-    lw      a4, -28(sp)
-    sw      a4, 15*4(a3)    # x15 (a5)
-    lw      a4, -32(sp)
-    sw      a4, 16*4(a3)    # x16 (a6)
-    sw      x17, 17*4(a3)   # x17 (a7)
-    sw      x18, 18*4(a3)   # x18 (s2)
-    sw      x19, 19*4(a3)   # x19 (s3)
-    sw      x20, 20*4(a3)   # x20 (s4)
-    sw      x21, 21*4(a3)   # x21 (s5)
-    sw      x22, 22*4(a3)   # x22 (s6)
-    sw      x23, 23*4(a3)   # x23 (s7)
-    sw      x24, 24*4(a3)   # x24 (s8)
-    sw      x25, 25*4(a3)   # x25 (s9)
-    sw      x26, 26*4(a3)   # x26 (s10)
-    sw      x27, 27*4(a3)   # x27 (s11)
-    sw      x28, 28*4(a3)   # x28 (t3)
-    sw      x29, 29*4(a3)   # x29 (t4)
-    sw      x30, 30*4(a3)   # x30 (t5)
-    sw      x31, 31*4(a3)   # x31 (t6)
-
-    addi    a2, a2, 32*4    # Increase depth
-    sw      a2, 0x00(a0)    # Save new stack depth
-
-    # Syscall arguments
-    lw      a5, -12(sp)     # Arg 1: Execution address
-
-    csrw    mepc, a5        # Change return address
-
-    j       ecall_end
-
-
-syscall_exit:
-    # Print "Syscall: Exit"
-    la      a0, bios_prefix
-    li      a1, 16
-    call    usart_send_string
-    la      a0, ascii_syscall_exit
-    li      a1, 15
-    call    usart_send_string
-
-    li      a0, 0x20000000 + 31 * 1024  # Globals
-    addi    a1, a0, 0x04    # Execution stack location
-
-    lw      a2, 0x00(a0)    # Execution stack depth
-    addi    a2, a2, -32*4   # Decrease depth
-    sw      a2, 0x00(a0)    # Save new stack depth
-
-    add     a3, a1, a2      # Last save point
-    lw      a4, (a3)        # Load from save point
-    csrw    mepc, a4        # Change return address
-
-    # Load registers
-    lw      a4, 1*4(a3)       # x1 (ra)
-    sw      a4, -4(sp)
-    lw      a4, 2*4(a3)    # x2 (sp)
-    csrw    mscratch, a4
-    lw      x3, 3*4(a3)    # x3 (gp)
-    lw      x4, 4*4(a3)    # x4 (tp)
-    lw      a4, 5*4(a3)    # x5 (t0)
-    sw      a4, -36(sp)
-    lw      a4, 6*4(a3)    # x6 (t1)
-    sw      a4, -40(sp)
-    lw      x7, 7*4(a3)    # x7 (t2)
-    lw      a4, 8*4(a3)    # x8 (s0/fp)
-    sw      a4, -44(sp)
-    lw      a4, 9*4(a3)    # x9 (s1)
-    sw      a4, -48(sp)
-    lw      a4, 10*4(a3)    # x10 (a0)
-    sw      a4, -8(sp)
-    lw      a4, 11*4(a3)    # x11 (a1)
-    sw      a4, -12(sp)
-    lw      a4, 12*4(a3)    # x12 (a2)
-    sw      a4, -16(sp)
-    lw      a4, 13*4(a3)    # x13 (a3)
-    sw      a4, -20(sp)
-    lw      a4, 14*4(a3)    # x14 (a4)
-    sw      a4, -24(sp)
-    lw      a4, 15*4(a3)    # x15 (a5)
-    sw      a4, -28(sp)
-    lw      a4, 16*4(a3)    # x16 (a6)
-    sw      a4, -32(sp)
-    lw      x17, 17*4(a3)   # x17 (a7)
-    lw      x18, 18*4(a3)   # x18 (s2)
-    lw      x19, 19*4(a3)   # x19 (s3)
-    lw      x20, 20*4(a3)   # x20 (s4)
-    lw      x21, 21*4(a3)   # x21 (s5)
-    lw      x22, 22*4(a3)   # x22 (s6)
-    lw      x23, 23*4(a3)   # x23 (s7)
-    lw      x24, 24*4(a3)   # x24 (s8)
-    lw      x25, 25*4(a3)   # x25 (s9)
-    lw      x26, 26*4(a3)   # x26 (s10)
-    lw      x27, 27*4(a3)   # x27 (s11)
-    lw      x28, 28*4(a3)   # x28 (t3)
-    lw      x29, 29*4(a3)   # x29 (t4)
-    lw      x30, 30*4(a3)   # x30 (t5)
-    lw      x31, 31*4(a3)   # x31 (t6)
-
-    # Syscall arguments
-    lw      a5, -12(sp)     # Arg 1: Exit code (ignored)
-
-    j       ecall_end
-
-
-syscall_put_byte:
-    # Syscall arguments
-    lw      a6, -12(sp)     # Arg 1: Byte/character to print
-
-    call    usart_put_byte
-
-    j       ecall_end
-
-# --------
-
 # RCU base: 0x4002 1000
 
 init_clocks:
@@ -362,7 +154,9 @@ init_clocks:
 init_globals:
     li      a0, 0x20000000 + 31 * 1024
 
-    sw      x0, 0x00(a0)    # Execution stack depth
+    sw      x0, 0x00(a0)    # Number of running programs (see trap.rs)
+    sw      x0, 0x04(a0)    # Output sink (see syslib::print), none
+    sw      x0, 0x08(a0)
 
     ret
 
@@ -809,21 +603,6 @@ return_address:
 interrupt_taken:
     .ascii  "Interrupt taken!\r\n"
 
-ascii_syscall_set_leds:
-    .ascii  "Syscall: Set LEDs\r\n"
-
-ascii_syscall_delay:
-    .ascii  "Syscall: Delay\r\n"
-
-ascii_syscall_exec:
-    .ascii  "Syscall: Exec\r\n"
-
-ascii_syscall_exit:
-    .ascii  "Syscall: Exit\r\n"
-
-unknown_interrupt_taken:
-    .ascii  "Unknown interrupt taken!\r\n"
-
 error:
     .ascii  "<too big>\r\n"
 
@@ -1,3 +1,6 @@
+#[cfg(not(test))]
+use core::arch::asm;
+
 use crate::bios_interface::ecall1;
 
 
@@ -25,12 +28,33 @@ pub(crate) fn set_leds(colors: &[u8]) {
     ecall1(2, color_bits);
 }
 
-pub(crate) fn delay(delay: u32) {
-    ecall1(1, delay);
+/// Runs the code at `address` as a program until it exits, returning its exit
+/// code and whether it was terminated with Ctrl-C. The program starts with
+/// `argc`, `argv` and `envp` in `a0` to `a2`.
+#[cfg(not(test))]
+pub(crate) fn exec(address: u32, argc: u32, argv: u32, envp: u32) -> (u32, bool) {
+    let exit_code: u32;
+    let terminated: u32;
+
+    // All other registers are restored when the program exits
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") 3 => exit_code,
+            inlateout("a1") address => terminated,
+            in("a2") argc,
+            in("a3") argv,
+            in("a4") envp,
+            options(nostack),
+        )
+    };
+
+    (exit_code, terminated != 0)
 }
 
-pub(crate) fn exec(address: u32) {
-    ecall1(3, address);
+#[cfg(test)]
+pub(crate) fn exec(_address: u32, _argc: u32, _argv: u32, _envp: u32) -> (u32, bool) {
+    unimplemented!("programs only run on the board");
 }
 
 pub(crate) fn exit(code: u32) {
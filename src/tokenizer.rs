@@ -0,0 +1,379 @@
+use crate::variables::{is_name_byte, Variables};
+
+pub(crate) const MAX_ARGS: usize = 16;
+
+/// A command line split into words, with quotes, escapes and variables
+/// resolved.
+///
+/// Words are separated by spaces or tabs. Inside single quotes everything is
+/// taken literally; inside double quotes and outside of quotes, a backslash
+/// starts one of the escapes `\n`, `\t`, `\r`, `\0`, `\e` or `\xNN`, or
+/// takes the next character literally, and `$NAME` or `$?` is replaced by a
+/// variable's value or the last exit status. Values are never split into
+/// several words.
+///
+/// An unquoted `>` or `>>` followed by a file name at the end of the line is
+/// not a word, but a request to redirect the output into that file.
+pub(crate) struct Tokens {
+    buffer: [u8; 256],
+    length: usize,
+    ends: [usize; MAX_ARGS],
+    count: usize,
+    // The kind of redirection and which word is the file name
+    redirect: Option<(Redirect, usize)>,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Redirect {
+    /// `>`, replacing the file.
+    Overwrite,
+    /// `>>`, adding to the end of the file.
+    Append,
+}
+
+impl Tokens {
+    pub(crate) fn parse(line: &[u8], variables: &Variables) -> Result<Self, &'static str> {
+        let mut tokens = Tokens {
+            buffer: [0; 256],
+            length: 0,
+            ends: [0; MAX_ARGS],
+            count: 0,
+            redirect: None,
+        };
+
+        let mut in_word = false;
+        let mut quote = None;
+        let mut bytes = line.iter().copied().peekable();
+
+        while let Some(byte) = bytes.next() {
+            let output = match (quote, byte) {
+                (None, b' ' | b'\t') => {
+                    if in_word {
+                        tokens.end_word()?;
+                        in_word = false;
+                    }
+                    continue;
+                }
+                (None, b'>') => {
+                    if tokens.redirect.is_some() {
+                        return Err("Output can only be redirected once.");
+                    }
+
+                    if in_word {
+                        tokens.end_word()?;
+                        in_word = false;
+                    }
+
+                    let redirect = match bytes.next_if_eq(&b'>') {
+                        Some(_) => Redirect::Append,
+                        None => Redirect::Overwrite,
+                    };
+                    tokens.redirect = Some((redirect, tokens.count));
+                    continue;
+                }
+                (None, b'\'' | b'"') => {
+                    quote = Some(byte);
+                    in_word = true;
+                    continue;
+                }
+                (Some(quote_byte), _) if byte == quote_byte => {
+                    quote = None;
+                    continue;
+                }
+                (Some(b'\''), _) => byte,
+                (_, b'\\') => parse_escape(&mut bytes)?,
+                (_, b'$') if bytes.peek() == Some(&b'?') => {
+                    bytes.next();
+                    tokens.push_number(variables.status)?;
+                    in_word = true;
+                    continue;
+                }
+                (_, b'$') if bytes.peek().is_some_and(|&byte| is_name_byte(byte)) => {
+                    let mut name = [0; 256];
+                    let mut name_length = 0;
+
+                    while let Some(byte) = bytes.next_if(|&byte| is_name_byte(byte)) {
+                        if name_length < name.len() {
+                            name[name_length] = byte;
+                            name_length += 1;
+                        }
+                    }
+
+                    // Unset variables expand to nothing, like in other shells
+                    let value = variables.get(&name[..name_length]).unwrap_or(b"");
+                    tokens.push(value)?;
+                    in_word = true;
+                    continue;
+                }
+                _ => byte,
+            };
+
+            tokens.push(&[output])?;
+            in_word = true;
+        }
+
+        if quote.is_some() {
+            return Err("Missing closing quote.");
+        }
+
+        if in_word {
+            tokens.end_word()?;
+        }
+
+        if let Some((_, file_name)) = tokens.redirect {
+            if tokens.count != file_name + 1 {
+                return Err("Expected a single file name after >.");
+            }
+        }
+
+        Ok(tokens)
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> Result<(), &'static str> {
+        let end = self.length + bytes.len();
+
+        if end > self.buffer.len() {
+            return Err("Line too long.");
+        }
+
+        self.buffer[self.length..end].copy_from_slice(bytes);
+        self.length = end;
+
+        Ok(())
+    }
+
+    fn push_number(&mut self, number: i32) -> Result<(), &'static str> {
+        let mut digits = [0; 11];
+        let mut start = digits.len();
+        let mut rest = number.unsigned_abs();
+
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (rest % 10) as u8;
+            rest /= 10;
+
+            if rest == 0 {
+                break;
+            }
+        }
+
+        if number < 0 {
+            start -= 1;
+            digits[start] = b'-';
+        }
+
+        self.push(&digits[start..])
+    }
+
+    fn end_word(&mut self) -> Result<(), &'static str> {
+        if self.count == MAX_ARGS {
+            return Err("Too many arguments.");
+        }
+
+        self.ends[self.count] = self.length;
+        self.count += 1;
+
+        Ok(())
+    }
+
+    /// Fills `argv` with the words and returns the used part of it.
+    pub(crate) fn words<'a>(&'a self, argv: &'a mut [&'a [u8]; MAX_ARGS]) -> &'a [&'a [u8]] {
+        let count = match self.redirect {
+            Some((_, file_name)) => file_name,
+            None => self.count,
+        };
+
+        for (index, word) in argv.iter_mut().enumerate().take(count) {
+            *word = self.word(index);
+        }
+
+        &argv[..count]
+    }
+
+    /// Returns the file to redirect the output into, if any.
+    pub(crate) fn redirect(&self) -> Option<(&[u8], Redirect)> {
+        self.redirect
+            .map(|(redirect, file_name)| (self.word(file_name), redirect))
+    }
+
+    fn word(&self, index: usize) -> &[u8] {
+        let start = match index {
+            0 => 0,
+            _ => self.ends[index - 1],
+        };
+
+        &self.buffer[start..self.ends[index]]
+    }
+}
+
+fn parse_escape(bytes: &mut impl Iterator<Item = u8>) -> Result<u8, &'static str> {
+    let byte = match bytes.next() {
+        Some(b'n') => b'\n',
+        Some(b't') => b'\t',
+        Some(b'r') => b'\r',
+        Some(b'0') => 0,
+        Some(b'e') => 0x1b,
+        Some(b'x') => {
+            let high = bytes.next().and_then(hex_digit);
+            let low = bytes.next().and_then(hex_digit);
+
+            match (high, low) {
+                (Some(high), Some(low)) => high << 4 | low,
+                _ => return Err("Expected two hex digits after \\x."),
+            }
+        }
+        Some(byte) => byte,
+        None => return Err("Nothing to escape at the end of the line."),
+    };
+
+    Ok(byte)
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &[u8]) -> Result<Tokens, &'static str> {
+        let mut variables = Variables::new();
+        variables.set(b"NAME", b"two words").unwrap();
+        variables.status = 130;
+
+        Tokens::parse(line, &variables)
+    }
+
+    fn words(line: &[u8]) -> Vec<Vec<u8>> {
+        let tokens = parse(line).unwrap();
+        let mut argv = [&[][..]; MAX_ARGS];
+
+        tokens
+            .words(&mut argv)
+            .iter()
+            .map(|word| word.to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn splits_words_on_spaces_and_tabs() {
+        assert_eq!(words(b"  echo \t a  b "), [&b"echo"[..], b"a", b"b"]);
+        assert!(words(b"   ").is_empty());
+    }
+
+    #[test]
+    fn quotes_keep_words_together() {
+        assert_eq!(
+            words(b"echo 'a b' \"c d\"e"),
+            [&b"echo"[..], b"a b", b"c de"]
+        );
+        assert_eq!(words(b"echo ''"), [&b"echo"[..], b""]);
+        assert_eq!(words(b"echo \"it's\""), [&b"echo"[..], b"it's"]);
+        assert_eq!(parse(b"echo 'a").err(), Some("Missing closing quote."));
+    }
+
+    #[test]
+    fn single_quotes_are_literal() {
+        assert_eq!(words(br"echo '\n $NAME >'"), [&b"echo"[..], br"\n $NAME >"]);
+    }
+
+    #[test]
+    fn backslash_escapes() {
+        assert_eq!(words(br"echo \n\t\r\0\e"), [&b"echo"[..], b"\n\t\r\0\x1b"]);
+        assert_eq!(
+            words(br#"echo \x41\x7e "\x0A""#),
+            [&b"echo"[..], b"A~", b"\n"]
+        );
+        assert_eq!(
+            words(br#"echo a\ b \" \\ \$NAME"#),
+            [&b"echo"[..], b"a b", b"\"", b"\\", b"$NAME"]
+        );
+    }
+
+    #[test]
+    fn bad_escapes_are_errors() {
+        assert_eq!(
+            parse(br"echo \x4").err(),
+            Some("Expected two hex digits after \\x.")
+        );
+        assert_eq!(
+            parse(br"echo \xg0").err(),
+            Some("Expected two hex digits after \\x.")
+        );
+        assert_eq!(
+            parse(br"echo \").err(),
+            Some("Nothing to escape at the end of the line.")
+        );
+    }
+
+    #[test]
+    fn variables_are_expanded_into_a_single_word() {
+        assert_eq!(words(b"echo $NAME"), [&b"echo"[..], b"two words"]);
+        assert_eq!(words(b"echo x$NAME.y"), [&b"echo"[..], b"xtwo words.y"]);
+        assert_eq!(words(b"echo \"$NAME\""), [&b"echo"[..], b"two words"]);
+        assert_eq!(words(b"echo $UNSET"), [&b"echo"[..], b""]);
+        assert_eq!(words(b"echo $ $-"), [&b"echo"[..], b"$", b"$-"]);
+    }
+
+    #[test]
+    fn status_is_expanded() {
+        assert_eq!(words(b"echo $? x$?y"), [&b"echo"[..], b"130", b"x130y"]);
+    }
+
+    #[test]
+    fn redirection_takes_the_last_word() {
+        let tokens = parse(b"echo a > out").unwrap();
+        let mut argv = [&[][..]; MAX_ARGS];
+
+        assert_eq!(tokens.words(&mut argv), [&b"echo"[..], b"a"]);
+        assert!(matches!(
+            tokens.redirect(),
+            Some((b"out", Redirect::Overwrite))
+        ));
+
+        let tokens = parse(b"echo a>>out").unwrap();
+        let mut argv = [&[][..]; MAX_ARGS];
+
+        assert_eq!(tokens.words(&mut argv), [&b"echo"[..], b"a"]);
+        assert!(matches!(
+            tokens.redirect(),
+            Some((b"out", Redirect::Append))
+        ));
+    }
+
+    #[test]
+    fn quoted_redirections_are_words() {
+        assert_eq!(
+            words(b"echo '>' \">>\" \\>"),
+            [&b"echo"[..], b">", b">>", b">"]
+        );
+        assert!(parse(b"echo '>' x").unwrap().redirect().is_none());
+    }
+
+    #[test]
+    fn misplaced_redirections_are_errors() {
+        let error = Some("Expected a single file name after >.");
+
+        assert_eq!(parse(b"echo >").err(), error);
+        assert_eq!(parse(b"echo > a b").err(), error);
+        assert_eq!(
+            parse(b"echo > a > b").err(),
+            Some("Output can only be redirected once.")
+        );
+    }
+
+    #[test]
+    fn limits_are_errors() {
+        assert_eq!(parse(&[b'x'; 257]).err(), Some("Line too long."));
+        assert_eq!(
+            parse(&b"x ".repeat(MAX_ARGS + 1)).err(),
+            Some("Too many arguments.")
+        );
+    }
+}
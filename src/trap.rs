@@ -0,0 +1,386 @@
+#[cfg(not(test))]
+use core::arch::asm;
+
+use crate::bios_interface::{cycle_count, put_char, try_get_char, CTRL_C, CYCLES_PER_MILLISECOND};
+
+/// The registers of the code that trapped, saved by `interrupt_handler` in
+/// start.s and restored from here when returning to it.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct TrapFrame {
+    /// Where to return to (mepc); x0 is always zero, so this takes its place.
+    pub(crate) pc: u32,
+    /// x1 to x31.
+    pub(crate) registers: [u32; 31],
+}
+
+impl TrapFrame {
+    /// Returns argument register `a<index>`.
+    fn a(&self, index: usize) -> u32 {
+        self.registers[9 + index]
+    }
+
+    fn set_a(&mut self, index: usize, value: u32) {
+        self.registers[9 + index] = value;
+    }
+}
+
+// Shared with start.s and syslib: the number of running programs, then the
+// output sink, then the registers of each program's caller
+const GLOBALS: usize = 0x20000000 + 31 * 1024;
+const PROGRAM_DEPTH: *mut usize = GLOBALS as *mut usize;
+const EXEC_STACK: *mut TrapFrame = (GLOBALS + 0x0C) as *mut TrapFrame;
+const MAX_PROGRAM_DEPTH: usize = 7;
+
+const MCAUSE_INTERRUPT: u32 = 1 << 31;
+const INTERRUPT_MACHINE_TIMER: u32 = 7;
+const EXCEPTION_ECALL_FROM_M_MODE: u32 = 11;
+
+// The timer runs at a quarter of the core clock
+const MTIME: *mut u32 = 0xD1000000 as *mut u32;
+const MTIMECMP: *mut u32 = 0xD1000008 as *mut u32;
+const TIMER_TICKS_PER_CHECK: u64 = 100000; // 50ms
+
+// Exit code as for SIGSEGV in other shells
+const EXIT_CODE_FAULT: u32 = 139;
+
+type Syscall = fn(&mut TrapFrame);
+
+/// Indexed by the syscall number in `a0`, minus one.
+const SYSCALLS: &[Syscall] = &[
+    syscall_delay,
+    syscall_set_leds,
+    syscall_exec,
+    syscall_exit,
+    syscall_put_byte,
+];
+
+// Output from here goes straight to the USART; printing with put! could
+// end up in a redirection sink, or in another ecall
+const BIOS_PREFIX: &str = "[\x1b[35mbios\x1b[0m] ";
+
+fn bios_message(message: &str) {
+    for &byte in BIOS_PREFIX.as_bytes().iter().chain(message.as_bytes()) {
+        put_char(byte);
+    }
+    put_char(b'\r');
+    put_char(b'\n');
+}
+
+#[derive(Debug, PartialEq)]
+enum Trap {
+    Timer,
+    UnknownInterrupt,
+    /// An ecall, with its index in the syscall table.
+    Syscall(usize),
+    UnknownSyscall,
+    /// Any other exception, e.g. an illegal instruction or a bus fault.
+    Fault,
+}
+
+fn classify(mcause: u32, syscall_number: u32, syscall_count: usize) -> Trap {
+    if mcause & MCAUSE_INTERRUPT != 0 {
+        return match mcause & !MCAUSE_INTERRUPT {
+            INTERRUPT_MACHINE_TIMER => Trap::Timer,
+            _ => Trap::UnknownInterrupt,
+        };
+    }
+
+    if mcause != EXCEPTION_ECALL_FROM_M_MODE {
+        return Trap::Fault;
+    }
+
+    match (syscall_number as usize).checked_sub(1) {
+        Some(index) if index < syscall_count => Trap::Syscall(index),
+        _ => Trap::UnknownSyscall,
+    }
+}
+
+/// Called by `interrupt_handler` in start.s for every exception and
+/// interrupt, on a stack of its own.
+#[no_mangle]
+extern "C" fn trap_handler(frame: &mut TrapFrame) {
+    handle_trap(frame, read_mcause(), SYSCALLS);
+}
+
+fn handle_trap(frame: &mut TrapFrame, mcause: u32, syscalls: &[Syscall]) {
+    match classify(mcause, frame.a(0), syscalls.len()) {
+        Trap::Timer => timer_interrupt(frame),
+        Trap::UnknownInterrupt => bios_message("Unknown interrupt taken!"),
+        Trap::Syscall(index) => {
+            // Continue after the ecall, which is never a compressed instruction
+            frame.pc += 4;
+            syscalls[index](frame);
+        }
+        Trap::UnknownSyscall => frame.pc += 4,
+        Trap::Fault => fault(frame),
+    }
+}
+
+/// Ends the program that caused an exception; retrying the instruction would
+/// only fault again.
+fn fault(frame: &mut TrapFrame) {
+    if unsafe { PROGRAM_DEPTH.read() } == 0 {
+        bios_message("Exception in the shell, halting.");
+
+        loop {
+            core::hint::spin_loop();
+        }
+    }
+
+    bios_message("Exception in the program, exiting it.");
+    exit_program(frame, EXIT_CODE_FAULT, false);
+}
+
+fn timer_interrupt(frame: &mut TrapFrame) {
+    schedule_timer_interrupt();
+    terminate_on_ctrl_c(frame);
+}
+
+fn schedule_timer_interrupt() {
+    let now = unsafe { (MTIME.add(1).read_volatile() as u64) << 32 | MTIME.read_volatile() as u64 };
+    let next = now + TIMER_TICKS_PER_CHECK;
+
+    unsafe {
+        // Keep mtimecmp in the future while updating it
+        MTIMECMP.add(1).write_volatile(u32::MAX);
+        MTIMECMP.write_volatile(next as u32);
+        MTIMECMP.add(1).write_volatile((next >> 32) as u32);
+    }
+}
+
+/// Ends all running programs if Ctrl-C was received, returning to the shell,
+/// and returns whether it did. Other received bytes are dropped; programs
+/// cannot read them anyway.
+fn terminate_on_ctrl_c(frame: &mut TrapFrame) -> bool {
+    if unsafe { PROGRAM_DEPTH.read() } == 0 || try_get_char() != Some(CTRL_C) {
+        return false;
+    }
+
+    // Unwind the whole execution stack: exiting from depth 1 restores the
+    // shell's registers from the bottom of it
+    unsafe { PROGRAM_DEPTH.write(1) };
+
+    // Exit code as for SIGINT in other shells
+    exit_program(frame, 130, true);
+    true
+}
+
+fn syscall_delay(frame: &mut TrapFrame) {
+    bios_message("Syscall: Delay");
+
+    let milliseconds = frame.a(1);
+    let deadline = cycle_count() + milliseconds as u64 * CYCLES_PER_MILLISECOND;
+
+    while cycle_count() < deadline {
+        if terminate_on_ctrl_c(frame) {
+            return;
+        }
+    }
+}
+
+fn syscall_set_leds(frame: &mut TrapFrame) {
+    bios_message("Syscall: Set LEDs");
+
+    // Red is PC13, green PA1 and blue PA2; the LEDs are on when the pin is low
+    const GPIOA: usize = 0x40010800;
+    const GPIOC: usize = 0x40011000;
+    const LEDS: [(usize, u32); 3] = [(GPIOC, 1 << 13), (GPIOA, 1 << 1), (GPIOA, 1 << 2)];
+
+    let mask = frame.a(1);
+
+    for (index, &(gpio, pin)) in LEDS.iter().enumerate() {
+        // GPIOx_BC (bit clear, turn on) and GPIOx_BOP (bit operate, turn off)
+        let bit_clear = (gpio + 0x14) as *mut u32;
+        let bit_operate = (gpio + 0x10) as *mut u32;
+
+        if mask & (1 << index) != 0 {
+            unsafe { bit_clear.write_volatile(pin) };
+        }
+        if mask & (1 << (index + 3)) != 0 {
+            unsafe { bit_operate.write_volatile(pin) };
+        }
+    }
+}
+
+/// Starts the program at `a1` with `argc`, `argv` and `envp` from `a2` to `a4`;
+/// the caller continues when the program exits, with its exit code in `a0` and
+/// whether it was terminated in `a1`.
+fn syscall_exec(frame: &mut TrapFrame) {
+    bios_message("Syscall: Exec");
+
+    let depth = unsafe { PROGRAM_DEPTH.read() };
+
+    if depth == MAX_PROGRAM_DEPTH {
+        bios_message("Too many programs running.");
+        frame.set_a(0, 1);
+        frame.set_a(1, 0);
+        return;
+    }
+
+    unsafe {
+        EXEC_STACK.add(depth).write(*frame);
+        PROGRAM_DEPTH.write(depth + 1);
+    }
+
+    frame.pc = frame.a(1);
+    frame.set_a(0, frame.a(2));
+    frame.set_a(1, frame.a(3));
+    frame.set_a(2, frame.a(4));
+
+    // Run the program with interrupts enabled, so that Ctrl-C can stop it;
+    // mtimecmp is not necessarily reset to anything that would ever fire
+    schedule_timer_interrupt();
+    set_previous_interrupt_enable(true);
+}
+
+fn syscall_exit(frame: &mut TrapFrame) {
+    bios_message("Syscall: Exit");
+
+    let exit_code = frame.a(1);
+
+    if unsafe { PROGRAM_DEPTH.read() } == 0 {
+        // Nothing to return to
+        return;
+    }
+
+    exit_program(frame, exit_code, false);
+}
+
+/// Returns to the caller of exec with the exit code in `a0`, and in `a1`
+/// whether the program was terminated.
+fn exit_program(frame: &mut TrapFrame, exit_code: u32, terminated: bool) {
+    let depth = unsafe { PROGRAM_DEPTH.read() } - 1;
+
+    unsafe {
+        PROGRAM_DEPTH.write(depth);
+        *frame = EXEC_STACK.add(depth).read();
+    }
+
+    frame.set_a(0, exit_code);
+    frame.set_a(1, terminated as u32);
+
+    // Interrupts stay enabled only when returning into another program
+    set_previous_interrupt_enable(depth > 0);
+}
+
+fn syscall_put_byte(frame: &mut TrapFrame) {
+    put_char(frame.a(1) as u8);
+}
+
+#[cfg(not(test))]
+fn read_mcause() -> u32 {
+    let mcause: u32;
+    unsafe { asm!("csrr {0}, mcause", out(reg) mcause, options(nomem, nostack)) };
+    mcause
+}
+
+/// Sets whether interrupts will be enabled after returning with `mret`.
+#[cfg(not(test))]
+fn set_previous_interrupt_enable(enable: bool) {
+    const MSTATUS_MPIE: u32 = 1 << 7;
+
+    unsafe {
+        if enable {
+            asm!("csrs mstatus, {0}", in(reg) MSTATUS_MPIE, options(nomem, nostack));
+        } else {
+            asm!("csrc mstatus, {0}", in(reg) MSTATUS_MPIE, options(nomem, nostack));
+        }
+    }
+}
+
+#[cfg(test)]
+fn read_mcause() -> u32 {
+    unimplemented!("CSRs only exist on the board");
+}
+
+#[cfg(test)]
+fn set_previous_interrupt_enable(_enable: bool) {
+    unimplemented!("CSRs only exist on the board");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame_with_syscall(number: u32, argument: u32) -> TrapFrame {
+        let mut frame = TrapFrame {
+            pc: 0x100,
+            registers: [0; 31],
+        };
+        frame.set_a(0, number);
+        frame.set_a(1, argument);
+        frame
+    }
+
+    fn fake_first(frame: &mut TrapFrame) {
+        frame.set_a(0, 100 + frame.a(1));
+    }
+
+    fn fake_second(frame: &mut TrapFrame) {
+        frame.set_a(0, 200 + frame.a(1));
+    }
+
+    const FAKE_SYSCALLS: &[Syscall] = &[fake_first, fake_second];
+
+    #[test]
+    fn trap_frame_matches_start_s() {
+        // 32 words, with a0 (x10) right after pc and x1 to x9
+        assert_eq!(core::mem::size_of::<TrapFrame>(), 128);
+
+        let frame = frame_with_syscall(5, 0);
+        assert_eq!(frame.registers[9], 5);
+    }
+
+    #[test]
+    fn ecall_picks_the_syscall_by_number() {
+        assert_eq!(classify(EXCEPTION_ECALL_FROM_M_MODE, 1, 5), Trap::Syscall(0));
+        assert_eq!(classify(EXCEPTION_ECALL_FROM_M_MODE, 5, 5), Trap::Syscall(4));
+    }
+
+    #[test]
+    fn unknown_syscall_numbers_are_rejected() {
+        assert_eq!(classify(EXCEPTION_ECALL_FROM_M_MODE, 0, 5), Trap::UnknownSyscall);
+        assert_eq!(classify(EXCEPTION_ECALL_FROM_M_MODE, 6, 5), Trap::UnknownSyscall);
+        assert_eq!(classify(EXCEPTION_ECALL_FROM_M_MODE, u32::MAX, 5), Trap::UnknownSyscall);
+    }
+
+    #[test]
+    fn interrupts_and_exceptions_are_told_apart() {
+        let timer = MCAUSE_INTERRUPT | INTERRUPT_MACHINE_TIMER;
+        assert_eq!(classify(timer, 1, 5), Trap::Timer);
+
+        // Same cause code as an ecall, but an interrupt
+        let interrupt = MCAUSE_INTERRUPT | EXCEPTION_ECALL_FROM_M_MODE;
+        assert_eq!(classify(interrupt, 1, 5), Trap::UnknownInterrupt);
+
+        // Same cause code as the timer (store access fault), but an exception
+        assert_eq!(classify(INTERRUPT_MACHINE_TIMER, 1, 5), Trap::Fault);
+        // Illegal instruction
+        assert_eq!(classify(2, 1, 5), Trap::Fault);
+    }
+
+    #[test]
+    fn syscalls_are_dispatched_and_skip_the_ecall() {
+        let mut frame = frame_with_syscall(2, 7);
+        handle_trap(&mut frame, EXCEPTION_ECALL_FROM_M_MODE, FAKE_SYSCALLS);
+
+        assert_eq!(frame.a(0), 207);
+        assert_eq!(frame.pc, 0x104);
+
+        let mut frame = frame_with_syscall(1, 7);
+        handle_trap(&mut frame, EXCEPTION_ECALL_FROM_M_MODE, FAKE_SYSCALLS);
+
+        assert_eq!(frame.a(0), 107);
+        assert_eq!(frame.pc, 0x104);
+    }
+
+    #[test]
+    fn unknown_syscalls_only_skip_the_ecall() {
+        let mut frame = frame_with_syscall(3, 7);
+        handle_trap(&mut frame, EXCEPTION_ECALL_FROM_M_MODE, FAKE_SYSCALLS);
+
+        assert_eq!(frame.a(0), 3);
+        assert_eq!(frame.pc, 0x104);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::{file_system::FileSystem, put};
+
+const MAX_VARIABLES: usize = 16;
+const MAX_NAME_SIZE: usize = 16;
+const MAX_VALUE_SIZE: usize = 64;
+const VARIABLES_FILE: &[u8] = b".variables";
+
+/// Shell variables, expanded by the tokenizer with `$NAME`.
+pub(crate) struct Variables {
+    names: [[u8; MAX_NAME_SIZE]; MAX_VARIABLES],
+    name_sizes: [usize; MAX_VARIABLES],
+    values: [[u8; MAX_VALUE_SIZE]; MAX_VARIABLES],
+    value_sizes: [usize; MAX_VARIABLES],
+    count: usize,
+    /// The exit status of the last command, expanded with `$?`.
+    pub(crate) status: i32,
+}
+
+impl Variables {
+    pub(crate) fn new() -> Self {
+        Self {
+            names: [[0; MAX_NAME_SIZE]; MAX_VARIABLES],
+            name_sizes: [0; MAX_VARIABLES],
+            values: [[0; MAX_VALUE_SIZE]; MAX_VARIABLES],
+            value_sizes: [0; MAX_VARIABLES],
+            count: 0,
+            status: 0,
+        }
+    }
+
+    pub(crate) fn get(&self, name: &[u8]) -> Option<&[u8]> {
+        self.position(name).map(|index| self.value(index))
+    }
+
+    pub(crate) fn set(&mut self, name: &[u8], value: &[u8]) -> Result<(), &'static str> {
+        if !is_valid_name(name) {
+            return Err("Names are letters, digits and underscores, not starting with a digit.");
+        }
+
+        if name.len() > MAX_NAME_SIZE {
+            return Err("Name too long.");
+        }
+
+        if value.len() > MAX_VALUE_SIZE {
+            return Err("Value too long.");
+        }
+
+        let index = match self.position(name) {
+            Some(index) => index,
+            None if self.count == MAX_VARIABLES => return Err("Too many variables."),
+            None => {
+                self.count += 1;
+                self.count - 1
+            }
+        };
+
+        self.names[index][..name.len()].copy_from_slice(name);
+        self.name_sizes[index] = name.len();
+        self.values[index][..value.len()].copy_from_slice(value);
+        self.value_sizes[index] = value.len();
+
+        Ok(())
+    }
+
+    /// Removes a variable, returning whether it existed.
+    pub(crate) fn remove(&mut self, name: &[u8]) -> bool {
+        let index = match self.position(name) {
+            Some(index) => index,
+            None => return false,
+        };
+
+        // Keep the others in the order they were set
+        let count = self.count;
+        self.names.copy_within(index + 1..count, index);
+        self.name_sizes.copy_within(index + 1..count, index);
+        self.values.copy_within(index + 1..count, index);
+        self.value_sizes.copy_within(index + 1..count, index);
+        self.count -= 1;
+
+        true
+    }
+
+    /// Returns each variable's name and value.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&[u8], &[u8])> + '_ {
+        (0..self.count).map(|index| (self.name(index), self.value(index)))
+    }
+
+    /// Loads the variables saved with `save`, if any.
+    pub(crate) fn load(&mut self, file_system: &FileSystem) {
+        let file = match file_system.file(VARIABLES_FILE) {
+            Some(file) => file,
+            None => return,
+        };
+
+        let mut line = [0; MAX_NAME_SIZE + 1 + MAX_VALUE_SIZE];
+        let mut length = 0;
+
+        for &byte in file_system.read_file(file).flatten() {
+            if byte != b'\n' {
+                if length < line.len() {
+                    line[length] = byte;
+                    length += 1;
+                }
+                continue;
+            }
+
+            let line = &line[..length];
+            length = 0;
+
+            if let Some(separator) = line.iter().position(|&byte| byte == b'=') {
+                let _ = self.set(&line[..separator], &line[separator + 1..]);
+            }
+        }
+    }
+
+    /// Saves the variables into a file, one `NAME=value` per line.
+    ///
+    /// Values containing newlines are cut short when loaded back.
+    pub(crate) fn save(&self, file_system: &mut FileSystem) {
+        let mut writer = match file_system.create_writer(VARIABLES_FILE) {
+            Some(writer) => writer,
+            None => {
+                put!("No free blocks left.");
+                return;
+            }
+        };
+
+        for (name, value) in self.iter() {
+            let written = writer.write(name).and_then(|_| writer.write(b"="));
+            let written = written.and_then(|_| writer.write(value));
+
+            if written.and_then(|_| writer.write(b"\n")).is_none() {
+                put!("File system full, variables not saved.");
+                return;
+            }
+        }
+
+        writer.finish();
+    }
+
+    fn position(&self, name: &[u8]) -> Option<usize> {
+        (0..self.count).find(|&index| self.name(index) == name)
+    }
+
+    fn name(&self, index: usize) -> &[u8] {
+        &self.names[index][..self.name_sizes[index]]
+    }
+
+    fn value(&self, index: usize) -> &[u8] {
+        &self.values[index][..self.value_sizes[index]]
+    }
+}
+
+pub(crate) fn is_name_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_'
+}
+
+fn is_valid_name(name: &[u8]) -> bool {
+    match name {
+        [first, ..] if first.is_ascii_digit() => false,
+        [] => false,
+        _ => name.iter().all(|&byte| is_name_byte(byte)),
+    }
+}
@@ -1,70 +1,629 @@
-use core::arch::asm;
+use crate::{
+    bios_interface::{get_char_timeout, put_char, sleep, CTRL_C},
+    commands::CommandError,
+    file_system::{FileSystem, FileWriter},
+    put,
+};
 
-use crate::{bios_interface::get_char, file_system::FileSystem, put, putn};
-
-const SOH: u8 = 0x01; // Start of Header
+const SOH: u8 = 0x01; // Start of Header, 128-byte packet
+const STX: u8 = 0x02; // Start of Text, 1024-byte packet
 const EOT: u8 = 0x04; // End of Transmission
 const ACK: u8 = 0x06; // Acknowledge
 const NAK: u8 = 0x15; // Not Acknowledge
 const CAN: u8 = 0x18; // Cancel
+const SUB: u8 = 0x1A; // Substitute, pads the last packet
+const CRC: u8 = b'C'; // Requests CRC-16 instead of the 8-bit checksum
+
+// Timeouts in milliseconds
+const START_TIMEOUT: u32 = 3000;
+const PACKET_TIMEOUT: u32 = 10000;
+const BYTE_TIMEOUT: u32 = 1000;
+
+const MAX_ERRORS: u32 = 10;
+
+// Attempts at starting in CRC mode before falling back to checksums
+const CRC_ATTEMPTS: u32 = 3;
+
+// Returned instead of an error message when Ctrl-C stops a transfer
+const TERMINATED: &str = "Terminated.";
+
+enum Packet {
+    Data { block_id: u8, size: usize },
+    EndOfTransmission,
+    Cancelled,
+    // Ctrl-C was typed on the console
+    Terminated,
+}
+
+enum PacketError {
+    Timeout,
+    UnexpectedByte,
+    BadBlockNumber,
+    BadCheckSum,
+}
+
+struct Statistics {
+    blocks: i32,
+    retransmissions: i32,
+}
+
+/// Receives a file into `file_name` over XMODEM, replacing any existing file
+/// only once the transfer has completed.
+///
+/// XMODEM pads the last packet with SUB bytes; they are stripped unless the
+/// exact `file_size` is known, in which case the content is cut to that size.
+///
+/// CRC-16 mode is requested first, falling back to the original checksum mode
+/// if the sender doesn't react, and 1K packets are accepted in either mode.
+pub(crate) fn receive_file(
+    file_system: &mut FileSystem,
+    file_name: &[u8],
+    file_size: Option<usize>,
+) -> Result<(), CommandError> {
+    let mut writer = match file_system.create_writer(file_name) {
+        Some(writer) => writer,
+        None => {
+            put!("No free blocks left.");
+            return Err(CommandError::Failed);
+        }
+    };
+
+    put!("Preparing to receive...");
 
-fn delay(time: u32) {
-    for _index in 0..100000 * time {
-        unsafe {
-            asm!("", options(nomem, nostack));
+    match receive_data(&mut writer, file_size, true) {
+        Ok(statistics) => {
+            writer.finish();
+
+            put!("Receive successful.");
+            put!("Received blocks:", statistics.blocks);
+            put!("Retransmissions:", statistics.retransmissions);
+            Ok(())
         }
+        Err(err) => Err(transfer_error(err)),
     }
 }
 
-pub(crate) fn receive_file(_file_system: &FileSystem) {
-    let mut buffer = [0; 1024];
+/// Receives any number of files over YMODEM, using the names and exact sizes
+/// sent in each file's header packet.
+pub(crate) fn receive_batch(file_system: &mut FileSystem) -> Result<(), CommandError> {
+    let mut header = [0; 1024];
+    let mut files: i32 = 0;
     let mut blocks: i32 = 0;
-    let mut check_sum_ok: i32 = 0;
+    let mut retransmissions: i32 = 0;
 
     put!("Preparing to receive...");
 
-    delay(100);
-
-    putn!(NAK);
-
     loop {
-        let mut check_sum: u8 = 0;
+        let size = match receive_header(&mut header) {
+            Ok(size) => size,
+            Err(TERMINATED) => return Err(CommandError::Terminated),
+            Err(err) => {
+                put!("Error:", err);
+                break;
+            }
+        };
 
-        let c = get_char();
+        let (file_name, file_size) = parse_header(&header[..size]);
+
+        // An empty header ends the batch
+        if file_name.is_empty() {
+            send(&[ACK]);
+            sleep(10);
 
-        if c == EOT {
-            putn!(ACK);
-            delay(1);
             put!("Receive successful.");
+            put!("Received files:", files);
             put!("Received blocks:", blocks);
-            put!("Successful checksums:", check_sum_ok);
-            break;
+            put!("Retransmissions:", retransmissions);
+            return Ok(());
         }
 
-        if c != SOH {
-            putn!(CAN);
-            put!("Error: Packet did not start with a SOH.");
-            put!("Instead it was:", c as u32 as i32);
-            break;
+        let mut writer = match file_system.create_writer(file_name) {
+            Some(writer) => writer,
+            None => {
+                cancel();
+                put!("Error: No free blocks left.");
+                break;
+            }
+        };
+
+        send(&[ACK]);
+
+        match receive_data(&mut writer, file_size, false) {
+            Ok(statistics) => {
+                // The sender is already waiting for a 'C' for the next file
+                writer.finish_quietly();
+
+                files += 1;
+                blocks += statistics.blocks;
+                retransmissions += statistics.retransmissions;
+            }
+            Err(TERMINATED) => return Err(CommandError::Terminated),
+            Err(err) => {
+                put!("Error:", err);
+                break;
+            }
+        }
+    }
+
+    put!("Files received before the error:", files);
+
+    Err(CommandError::Failed)
+}
+
+/// Waits for a YMODEM header, which is block 0, and returns its size.
+fn receive_header(buffer: &mut [u8; 1024]) -> Result<usize, &'static str> {
+    let mut errors = 0;
+
+    send(&[CRC]);
+
+    loop {
+        if errors >= MAX_ERRORS {
+            cancel();
+            return Err("Too many errors, transfer aborted.");
+        }
+
+        match receive_packet(buffer, START_TIMEOUT, true) {
+            Ok(Packet::Data { block_id: 0, size }) => return Ok(size),
+            Ok(Packet::Data { .. }) => {
+                cancel();
+                return Err("Expected a header packet.");
+            }
+            Ok(Packet::EndOfTransmission) => {
+                // The sender missed the ACK for the previous file's EOT
+                send(&[ACK]);
+            }
+            Ok(Packet::Cancelled) => return Err("Transfer cancelled by sender."),
+            Ok(Packet::Terminated) => {
+                cancel();
+                return Err(TERMINATED);
+            }
+            Err(PacketError::Timeout) => {
+                errors += 1;
+                send(&[CRC]);
+            }
+            Err(_) => {
+                purge();
+                errors += 1;
+                send(&[NAK]);
+            }
+        }
+    }
+}
+
+/// Splits a header into the file name and the optional decimal file size,
+/// which follows the name's terminating NUL byte.
+fn parse_header(header: &[u8]) -> (&[u8], Option<usize>) {
+    let mut fields = header.splitn(2, |&byte| byte == 0);
+    let file_name = fields.next().unwrap_or(b"");
+    let rest = fields.next().unwrap_or(b"");
+
+    let mut file_size = None;
+
+    for &byte in rest.iter().take_while(|byte| byte.is_ascii_digit()) {
+        let size = file_size.unwrap_or(0usize);
+        file_size = Some(size.saturating_mul(10).saturating_add((byte - b'0') as usize));
+    }
+
+    (file_name, file_size)
+}
+
+/// Receives data packets from block 1 up to the end of transmission.
+///
+/// In XMODEM the sender waits for the receiver to choose between CRC and
+/// checksum mode; YMODEM always uses CRC, so `crc_fallback` is only set for
+/// the former.
+fn receive_data(
+    writer: &mut FileWriter,
+    file_size: Option<usize>,
+    crc_fallback: bool,
+) -> Result<Statistics, &'static str> {
+    // The previous packet is held back until the next one arrives, since only
+    // the last one may contain padding.
+    let mut pending = [0; 1024];
+    let mut pending_size = 0;
+    let mut buffer = [0; 1024];
+    let mut remaining = file_size.unwrap_or(usize::MAX);
+    let mut expected_block: u8 = 1;
+    let mut blocks: i32 = 0;
+    let mut errors = 0;
+    let mut retransmissions: i32 = 0;
+    let mut crc_mode = true;
+
+    send(&[CRC]);
+
+    loop {
+        if errors >= MAX_ERRORS {
+            cancel();
+            return Err("Too many errors, transfer aborted.");
+        }
+
+        let timeout = if blocks == 0 {
+            START_TIMEOUT
+        } else {
+            PACKET_TIMEOUT
+        };
+
+        match receive_packet(&mut buffer, timeout, crc_mode) {
+            Ok(Packet::Data { block_id, size }) if block_id == expected_block => {
+                if writer.write(&pending[..pending_size]).is_none() {
+                    cancel();
+                    return Err("File system full, file not saved.");
+                }
+
+                pending_size = remaining.min(size);
+                remaining -= pending_size;
+                pending[..pending_size].copy_from_slice(&buffer[..pending_size]);
+
+                expected_block = expected_block.wrapping_add(1);
+                blocks += 1;
+                errors = 0;
+                send(&[ACK]);
+            }
+            Ok(Packet::Data { block_id, .. }) if block_id == expected_block.wrapping_sub(1) => {
+                // The sender missed our ACK and repeated the packet
+                retransmissions += 1;
+                send(&[ACK]);
+            }
+            Ok(Packet::Data { .. }) => {
+                cancel();
+                return Err("Block out of sequence.");
+            }
+            Ok(Packet::EndOfTransmission) => {
+                send(&[ACK]);
+                sleep(10);
+
+                let mut last_packet = &pending[..pending_size];
+                if file_size.is_none() {
+                    while let [rest @ .., SUB] = last_packet {
+                        last_packet = rest;
+                    }
+                }
+
+                if writer.write(last_packet).is_none() {
+                    return Err("File system full, file not saved.");
+                }
+
+                return Ok(Statistics {
+                    blocks,
+                    retransmissions,
+                });
+            }
+            Ok(Packet::Cancelled) => return Err("Transfer cancelled by sender."),
+            Ok(Packet::Terminated) => {
+                cancel();
+                return Err(TERMINATED);
+            }
+            Err(PacketError::Timeout) if blocks == 0 => {
+                errors += 1;
+
+                if crc_fallback && crc_mode && errors >= CRC_ATTEMPTS {
+                    crc_mode = false;
+                }
+
+                // Repeat the start request, which also selects the mode
+                if crc_mode {
+                    send(&[CRC]);
+                } else {
+                    send(&[NAK]);
+                }
+            }
+            Err(PacketError::Timeout) => {
+                errors += 1;
+                retransmissions += 1;
+                send(&[NAK]);
+            }
+            Err(_) => {
+                purge();
+                errors += 1;
+                retransmissions += 1;
+                send(&[NAK]);
+            }
+        }
+    }
+}
+
+fn receive_packet(
+    buffer: &mut [u8; 1024],
+    timeout: u32,
+    crc_mode: bool,
+) -> Result<Packet, PacketError> {
+    let size = match get_char_timeout(timeout).ok_or(PacketError::Timeout)? {
+        SOH => 128,
+        STX => 1024,
+        EOT => return Ok(Packet::EndOfTransmission),
+        CAN if second_cancel() => return Ok(Packet::Cancelled),
+        CTRL_C => return Ok(Packet::Terminated),
+        _ => return Err(PacketError::UnexpectedByte),
+    };
+
+    let block_id = get_byte()?;
+    let block_id_reversed = get_byte()?;
+
+    let mut check_sum: u8 = 0;
+    let mut crc: u16 = 0;
+
+    for slot in buffer[..size].iter_mut() {
+        let byte = get_byte()?;
+        *slot = byte;
+
+        check_sum = check_sum.wrapping_add(byte);
+        crc = crc16_update(crc, byte);
+    }
+
+    let check_ok = if crc_mode {
+        let expected_crc = u16::from_be_bytes([get_byte()?, get_byte()?]);
+        crc == expected_crc
+    } else {
+        check_sum == get_byte()?
+    };
+
+    if block_id != !block_id_reversed {
+        return Err(PacketError::BadBlockNumber);
+    }
+
+    if !check_ok {
+        return Err(PacketError::BadCheckSum);
+    }
+
+    Ok(Packet::Data { block_id, size })
+}
+
+/// CRC-16/XMODEM: polynomial 0x1021, initial value 0, most significant bit first.
+fn crc16_update(crc: u16, byte: u8) -> u16 {
+    let mut crc = crc ^ (byte as u16) << 8;
+
+    for _ in 0..8 {
+        crc = if crc & 0x8000 != 0 {
+            crc << 1 ^ 0x1021
+        } else {
+            crc << 1
+        };
+    }
+
+    crc
+}
+
+fn get_byte() -> Result<u8, PacketError> {
+    get_char_timeout(BYTE_TIMEOUT).ok_or(PacketError::Timeout)
+}
+
+/// Discards incoming bytes until the line goes quiet, so that the sender's
+/// retransmission starts at a packet boundary.
+fn purge() {
+    while get_char_timeout(BYTE_TIMEOUT).is_some() {}
+}
+
+/// A single CAN may be line noise, a cancelled transfer always sends two.
+fn second_cancel() -> bool {
+    get_char_timeout(BYTE_TIMEOUT) == Some(CAN)
+}
+
+/// Sends protocol bytes straight to the USART; unlike `putn!`, this cannot
+/// end up in a redirection sink, which would leave the other side waiting.
+fn send(bytes: &[u8]) {
+    for &byte in bytes {
+        put_char(byte);
+    }
+}
+
+/// Reports a failed transfer; the shell reports a terminated one itself.
+fn transfer_error(err: &'static str) -> CommandError {
+    if err == TERMINATED {
+        return CommandError::Terminated;
+    }
+
+    put!("Error:", err);
+    CommandError::Failed
+}
+
+fn cancel() {
+    send(&[CAN, CAN, CAN]);
+    purge();
+}
+
+/// Sends a file to the host over XMODEM, using CRC-16 and 1K packets if the
+/// receiver asks for CRC mode and 128-byte checksum packets otherwise.
+pub(crate) fn send_file(file_system: &FileSystem, file_name: &[u8]) -> Result<(), CommandError> {
+    let file = match file_system.file(file_name) {
+        Some(file) => file,
+        None => {
+            put!("File not found:", file_name);
+            return Err(CommandError::Failed);
         }
+    };
 
-        let _block_id = get_char();
-        let _block_id_reversed = get_char();
+    put!("Preparing to send...");
 
-        for index in 0..128 {
-            let byte = get_char();
-            buffer[index] = byte;
+    let result = wait_for_receiver().and_then(|crc_mode| {
+        let content = file_system.read_file(file).flatten().copied();
+        send_data(content, crc_mode)
+    });
 
-            check_sum = check_sum.wrapping_add(byte);
+    sleep(10);
+
+    match result {
+        Ok(statistics) => {
+            put!("Send successful.");
+            put!("Sent blocks:", statistics.blocks);
+            put!("Retransmissions:", statistics.retransmissions);
+            Ok(())
+        }
+        Err(err) => Err(transfer_error(err)),
+    }
+}
+
+/// Sends a single file over YMODEM, which also tells the receiver the file's
+/// name and exact size.
+pub(crate) fn send_batch(file_system: &FileSystem, file_name: &[u8]) -> Result<(), CommandError> {
+    let file = match file_system.file(file_name) {
+        Some(file) => file,
+        None => {
+            put!("File not found:", file_name);
+            return Err(CommandError::Failed);
+        }
+    };
+
+    put!("Preparing to send...");
+
+    let mut header = [0; 128];
+    let name_size = file_name.len().min(100);
+    header[..name_size].copy_from_slice(&file_name[..name_size]);
+    write_decimal(&mut header[name_size + 1..], file_system.file_size(file));
+
+    let content = file_system.read_file(file).flatten().copied();
+    let result = send_ymodem(&header, content);
+
+    sleep(10);
+
+    match result {
+        Ok(statistics) => {
+            put!("Send successful.");
+            put!("Sent blocks:", statistics.blocks);
+            put!("Retransmissions:", statistics.retransmissions);
+            Ok(())
         }
+        Err(err) => Err(transfer_error(err)),
+    }
+}
+
+fn send_ymodem(
+    header: &[u8; 128],
+    content: impl Iterator<Item = u8>,
+) -> Result<Statistics, &'static str> {
+    wait_for_receiver()?;
+    send_packet(header, 0, true)?;
+
+    wait_for_receiver()?;
+    let statistics = send_data(content, true)?;
 
-        let expected_check_sum = get_char();
+    // An empty header ends the batch
+    wait_for_receiver()?;
+    send_packet(&[0; 128], 0, true)?;
+
+    Ok(statistics)
+}
+
+/// Waits for the receiver to request the transfer and returns whether it
+/// asked for CRC mode.
+fn wait_for_receiver() -> Result<bool, &'static str> {
+    for _ in 0..MAX_ERRORS {
+        match get_char_timeout(PACKET_TIMEOUT) {
+            Some(CRC) => return Ok(true),
+            Some(NAK) => return Ok(false),
+            Some(CAN) if second_cancel() => return Err("Transfer cancelled by receiver."),
+            Some(CTRL_C) => return Err(TERMINATED),
+            _ => (),
+        }
+    }
 
+    Err("Receiver did not start the transfer.")
+}
+
+fn send_data(
+    mut content: impl Iterator<Item = u8>,
+    crc_mode: bool,
+) -> Result<Statistics, &'static str> {
+    let mut buffer = [0; 1024];
+    let mut block_id: u8 = 1;
+    let mut blocks: i32 = 0;
+    let mut retransmissions: i32 = 0;
+
+    // Checksum mode implies the original protocol, which has no 1K packets
+    let packet_size = if crc_mode { 1024 } else { 128 };
+
+    loop {
+        let mut size = 0;
+
+        for slot in buffer[..packet_size].iter_mut() {
+            match content.next() {
+                Some(byte) => *slot = byte,
+                None => break,
+            }
+            size += 1;
+        }
+
+        if size == 0 {
+            break;
+        }
+
+        // Don't waste a 1K packet on a short tail
+        let size_with_padding = if size <= 128 { 128 } else { packet_size };
+        buffer[size..size_with_padding].fill(SUB);
+
+        retransmissions += send_packet(&buffer[..size_with_padding], block_id, crc_mode)?;
+
+        block_id = block_id.wrapping_add(1);
         blocks += 1;
-        if check_sum == expected_check_sum {
-            check_sum_ok += 1;
+    }
+
+    for _ in 0..MAX_ERRORS {
+        send(&[EOT]);
+
+        match get_char_timeout(PACKET_TIMEOUT) {
+            Some(ACK) => {
+                return Ok(Statistics {
+                    blocks,
+                    retransmissions,
+                })
+            }
+            Some(CAN) if second_cancel() => return Err("Transfer cancelled by receiver."),
+            _ => retransmissions += 1,
         }
+    }
+
+    Err("End of transmission not acknowledged.")
+}
+
+/// Sends a 128 or 1024 byte packet until it is acknowledged, and returns the
+/// number of retransmissions that were needed.
+fn send_packet(data: &[u8], block_id: u8, crc_mode: bool) -> Result<i32, &'static str> {
+    let mut check_sum: u8 = 0;
+    let mut crc: u16 = 0;
+
+    for &byte in data {
+        check_sum = check_sum.wrapping_add(byte);
+        crc = crc16_update(crc, byte);
+    }
+
+    for retransmissions in 0..MAX_ERRORS as i32 {
+        let start = if data.len() == 1024 { STX } else { SOH };
+        send(&[start, block_id, !block_id]);
+        send(data);
+
+        if crc_mode {
+            let [high, low] = crc.to_be_bytes();
+            send(&[high, low]);
+        } else {
+            send(&[check_sum]);
+        }
+
+        match get_char_timeout(PACKET_TIMEOUT) {
+            Some(ACK) => return Ok(retransmissions),
+            Some(CAN) if second_cancel() => return Err("Transfer cancelled by receiver."),
+            Some(NAK) => (),
+            _ => purge(),
+        }
+    }
+
+    send(&[CAN, CAN, CAN]);
+    Err("Too many errors, transfer aborted.")
+}
+
+fn write_decimal(buffer: &mut [u8], mut value: usize) {
+    let mut digits = [0; 10];
+    let mut count = 0;
+
+    loop {
+        digits[count] = b'0' + (value % 10) as u8;
+        count += 1;
+        value /= 10;
+
+        if value == 0 {
+            break;
+        }
+    }
 
-        putn!(ACK);
+    for (slot, &digit) in buffer.iter_mut().zip(digits[..count].iter().rev()) {
+        *slot = digit;
     }
 }
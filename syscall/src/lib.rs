@@ -14,6 +14,27 @@ fn ecall1(syscall_number: u8, arg1: u32) {
     };
 }
 
+/// Returns `a0` and `a1` as the syscall left them.
+fn ecall4(syscall_number: u8, arg1: u32, arg2: u32, arg3: u32, arg4: u32) -> (u32, u32) {
+    let result0: u32;
+    let result1: u32;
+
+    // Not nomem, since the syscall may run code that uses memory
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") syscall_number as u32 => result0,
+            inlateout("a1") arg1 => result1,
+            in("a2") arg2,
+            in("a3") arg3,
+            in("a4") arg4,
+            options(nostack),
+        )
+    };
+
+    (result0, result1)
+}
+
 pub fn delay(delay: u32) {
     ecall1(1, delay);
 }
@@ -22,8 +43,12 @@ pub fn set_leds(color_bits: u32) {
     ecall1(2, color_bits);
 }
 
-pub fn exec(address: u32) {
-    ecall1(3, address);
+/// Runs the program at `address` until it exits, returning its exit code and
+/// whether it was terminated with Ctrl-C.
+pub fn exec(address: u32, argc: u32, argv: *const *const u8, envp: *const *const u8) -> (u32, bool) {
+    let (exit_code, terminated) = ecall4(3, address, argc, argv as u32, envp as u32);
+
+    (exit_code, terminated != 0)
 }
 
 pub fn exit(code: u32) -> ! {
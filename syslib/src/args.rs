@@ -0,0 +1,87 @@
+use core::slice;
+
+/// The arguments the program was run with, starting with its file name.
+pub struct Args {
+    argv: *const *const u8,
+    remaining: usize,
+}
+
+/// The shell variables the program was run with, as names and values.
+pub struct Vars {
+    envp: *const *const u8,
+}
+
+/// Returns the arguments in the `argc` and `argv` that `_start` gets.
+///
+/// # Safety
+///
+/// `argv` must point to `argc` null-terminated strings, as passed to `_start`.
+pub unsafe fn args(argc: u32, argv: *const *const u8) -> Args {
+    Args {
+        argv,
+        remaining: argc as usize,
+    }
+}
+
+/// Returns the shell variables in the `envp` that `_start` gets.
+///
+/// # Safety
+///
+/// `envp` must point to `NAME=value` strings ending with a null pointer, as
+/// passed to `_start`.
+pub unsafe fn vars(envp: *const *const u8) -> Vars {
+    Vars { envp }
+}
+
+impl Iterator for Args {
+    type Item = &'static [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let arg = unsafe { c_string(*self.argv) };
+
+        self.argv = unsafe { self.argv.add(1) };
+        self.remaining -= 1;
+
+        Some(arg)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl ExactSizeIterator for Args {}
+
+impl Iterator for Vars {
+    type Item = (&'static [u8], &'static [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let variable = unsafe { *self.envp };
+
+        if variable.is_null() {
+            return None;
+        }
+
+        self.envp = unsafe { self.envp.add(1) };
+
+        let variable = unsafe { c_string(variable) };
+        let equals = variable.iter().position(|&byte| byte == b'=').unwrap_or(variable.len());
+        let (name, value) = variable.split_at(equals);
+
+        Some((name, value.get(1..).unwrap_or(&[])))
+    }
+}
+
+unsafe fn c_string(string: *const u8) -> &'static [u8] {
+    let mut length = 0;
+
+    while unsafe { *string.add(length) } != 0 {
+        length += 1;
+    }
+
+    unsafe { slice::from_raw_parts(string, length) }
+}
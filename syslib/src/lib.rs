@@ -1,3 +1,6 @@
 #![no_std]
 
+pub mod args;
 pub mod print;
+
+pub use args::{args, vars};
@@ -1,7 +1,58 @@
+/// Where output goes instead of the USART. Separately compiled programs read
+/// it too, so it only uses types with a fixed layout.
+#[repr(C)]
+struct Sink {
+    put_byte: Option<extern "C" fn(*mut (), u8)>,
+    context: *mut (),
+}
+
+// Shared by the OS and the programs it runs, in the globals page after the
+// execution stack depth; zeroed (no sink) at boot
+const SINK: *mut Sink = (0x20000000 + 31 * 1024 + 4) as *mut Sink;
+
 fn put_char(byte: u8) {
+    let sink = unsafe { SINK.read() };
+
+    match sink.put_byte {
+        Some(put_byte) => put_byte(sink.context, byte),
+        None => console_put_byte(byte),
+    }
+}
+
+/// Returns whether the output currently goes to a sink set with `with_output`.
+pub fn is_redirected() -> bool {
+    unsafe { (*SINK).put_byte.is_some() }
+}
+
+/// Runs `f` with everything it prints given to `sink` instead of the USART.
+pub fn with_output<R, F: FnMut(u8)>(sink: &mut F, f: impl FnOnce() -> R) -> R {
+    // The sink is only reachable until it is put back below
+    let sink = Sink {
+        put_byte: Some(call_sink::<F>),
+        context: sink as *mut F as *mut (),
+    };
+
+    let previous = unsafe { SINK.replace(sink) };
+    let result = f();
+    unsafe { SINK.write(previous) };
+
+    result
+}
+
+extern "C" fn call_sink<F: FnMut(u8)>(context: *mut (), byte: u8) {
+    let sink = unsafe { &mut *(context as *mut F) };
+    sink(byte);
+}
+
+#[cfg(target_arch = "riscv32")]
+fn console_put_byte(byte: u8) {
     syscall::put_byte(byte);
 }
 
+// Host builds are only for the kernel's tests, which have no console
+#[cfg(not(target_arch = "riscv32"))]
+fn console_put_byte(_byte: u8) {}
+
 pub trait Printable {
     fn print(&self);
 }
@@ -0,0 +1,82 @@
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    mem::MaybeUninit,
+    thread,
+};
+
+use serialport::SerialPort;
+
+// Ctrl-], as in telnet
+const EXIT_KEY: u8 = 0x1D;
+
+/// Forwards keystrokes to the board and its output to the terminal until the
+/// exit key is pressed.
+pub(crate) fn run(port: Box<dyn SerialPort>) -> io::Result<()> {
+    let mut reader = port.try_clone()?;
+    let mut writer = port;
+
+    eprintln!("Connected, press Ctrl-] to exit.\r");
+
+    let _raw_mode = RawMode::enable()?;
+
+    thread::spawn(move || {
+        let mut buffer = [0; 256];
+        let mut stdout = io::stdout();
+
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(size) => {
+                    let _ = stdout.write_all(&buffer[..size]);
+                    let _ = stdout.flush();
+                }
+                Err(err) if err.kind() == ErrorKind::TimedOut => (),
+                Err(_) => break,
+            }
+        }
+    });
+
+    let mut byte = [0];
+    let mut stdin = io::stdin();
+
+    while stdin.read(&mut byte)? == 1 {
+        if byte[0] == EXIT_KEY {
+            break;
+        }
+
+        writer.write_all(&byte)?;
+    }
+
+    Ok(())
+}
+
+/// Passes every key through unprocessed, restoring the terminal when dropped.
+struct RawMode {
+    original: libc::termios,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        let mut termios = MaybeUninit::uninit();
+
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let original = unsafe { termios.assume_init() };
+        let mut raw = original;
+
+        unsafe { libc::cfmakeraw(&mut raw) };
+
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original) };
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    env,
+    io,
+    path::Path,
+    process::ExitCode,
+    time::Duration,
+};
+
+use port::{read_until, Port};
+
+mod console;
+mod port;
+mod xmodem;
+
+const USAGE: &str = "\
+Usage: tty-tool [--port <path>] [--baud <rate>] <command>
+
+Commands:
+    upload <file>...    Upload files with XMODEM and check the shell's reply
+    console             Interactive terminal, exit with Ctrl-]
+
+The port can be a serial device or a pseudo-terminal, and defaults to
+$TTY_PORT or /dev/ttyUSB0.";
+
+// What the board's shell prints when it's ready for the next command
+const PROMPT: &[u8] = b"\x1b[1;34m>\x1b[0m ";
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Saving the file system after a transfer erases and writes flash pages
+const SAVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn main() -> ExitCode {
+    match run(env::args().skip(1).collect()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: Vec<String>) -> io::Result<()> {
+    let mut port_path = env::var("TTY_PORT").unwrap_or_else(|_| "/dev/ttyUSB0".to_string());
+    let mut baud_rate = 115200;
+    let mut args = args.into_iter();
+
+    let command = loop {
+        match args.next().as_deref() {
+            Some("--port") => port_path = args.next().ok_or_else(usage_error)?,
+            Some("--baud") => {
+                baud_rate = args
+                    .next()
+                    .and_then(|rate| rate.parse().ok())
+                    .ok_or_else(usage_error)?
+            }
+            Some(command) => break command.to_string(),
+            None => return Err(usage_error()),
+        }
+    };
+
+    let mut port = port::open(&port_path, baud_rate)?;
+
+    match command.as_str() {
+        "upload" => {
+            let files: Vec<String> = args.collect();
+
+            if files.is_empty() {
+                return Err(usage_error());
+            }
+
+            for file in files {
+                upload(&mut port, Path::new(&file))?;
+            }
+
+            Ok(())
+        }
+        "console" => console::run(port),
+        _ => Err(usage_error()),
+    }
+}
+
+/// Uploads a file with the shell's `rx` command, under its base name.
+fn upload(port: &mut impl Port, path: &Path) -> io::Result<()> {
+    let content = std::fs::read(path)?;
+
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| !name.contains(char::is_control))
+        .ok_or_else(|| io::Error::other(format!("unusable file name: {}", path.display())))?;
+
+    // Start from a fresh prompt, in case something was left on the line
+    port.write_all(b"\r")?;
+    read_until(port, PROMPT, REPLY_TIMEOUT)?;
+
+    port.write_all(format!("rx {} {}\r", quote(file_name), content.len()).as_bytes())?;
+    read_until(port, b"Preparing to receive...\r\n", REPLY_TIMEOUT)?;
+
+    eprint!("Uploading {file_name}... ");
+
+    xmodem::send(port, &content, |sent| {
+        eprint!("\rUploading {file_name}... {sent}/{} bytes", content.len());
+    })?;
+
+    eprintln!();
+
+    let reply = read_until(port, PROMPT, SAVE_TIMEOUT)?;
+    let reply = String::from_utf8_lossy(&reply);
+
+    if !reply.contains("Receive successful.") {
+        return Err(io::Error::other(format!("upload failed: {}", reply.trim_end())));
+    }
+
+    eprintln!("Uploaded {file_name}.");
+
+    Ok(())
+}
+
+/// Quotes a word for the shell's tokenizer if it contains anything that would
+/// otherwise be split, escaped, expanded or taken as a redirection.
+fn quote(word: &str) -> String {
+    if !word.contains([' ', '\t', '\'', '"', '\\', '$', '>']) {
+        return word.to_string();
+    }
+
+    let mut quoted = String::from("\"");
+
+    for character in word.chars() {
+        // Inside double quotes, a backslash takes the next character literally
+        if matches!(character, '"' | '\\' | '$') {
+            quoted.push('\\');
+        }
+        quoted.push(character);
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+fn usage_error() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, USAGE)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{Read, Write},
+        path::PathBuf,
+        process, thread,
+    };
+
+    use super::*;
+    use crate::port::{PseudoTerminal, ScriptedPort};
+
+    const SOH: u8 = 0x01;
+    const EOT: u8 = 0x04;
+    const ACK: u8 = 0x06;
+
+    fn test_file(test_name: &str, content: &[u8]) -> PathBuf {
+        let directory = env::temp_dir().join(format!("tty-tool-{}-{test_name}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let path = directory.join("hello.txt");
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn reply_after_transfer(message: &str) -> Vec<u8> {
+        let mut reply = vec![ACK];
+        reply.extend_from_slice(message.as_bytes());
+        reply.extend_from_slice(PROMPT);
+        reply
+    }
+
+    #[test]
+    fn upload_runs_rx_and_checks_the_reply() {
+        let path = test_file("success", b"hello");
+        let reply = reply_after_transfer("Receive successful.\r\n");
+        let mut port = ScriptedPort::new(
+            &[],
+            &[PROMPT, b"Preparing to receive...\r\nC", &[ACK], &reply],
+        );
+
+        upload(&mut port, &path).unwrap();
+
+        assert_eq!(port.written[0], b"\r");
+        assert_eq!(port.written[1], b"rx hello.txt 5\r");
+        assert_eq!(port.written.len(), 4);
+    }
+
+    #[test]
+    fn upload_fails_if_the_board_reports_an_error() {
+        let path = test_file("failure", b"hello");
+        let reply = reply_after_transfer("Error: File system full.\r\n");
+        let mut port = ScriptedPort::new(
+            &[],
+            &[PROMPT, b"Preparing to receive...\r\nC", &[ACK], &reply],
+        );
+
+        let err = upload(&mut port, &path).unwrap_err();
+
+        assert!(err.to_string().contains("File system full."));
+    }
+
+    #[test]
+    fn upload_over_a_pseudo_terminal() {
+        let path = test_file("pty", b"hello");
+        let terminal = PseudoTerminal::open().unwrap();
+        let mut board = terminal.board.try_clone().unwrap();
+
+        // Plays the shell's side of the upload, returning the command line
+        let shell = thread::spawn(move || {
+            let mut byte = [0];
+            board.read_exact(&mut byte).unwrap();
+            assert_eq!(byte, *b"\r");
+            board.write_all(PROMPT).unwrap();
+
+            let mut command = Vec::new();
+            while !command.ends_with(b"\r") {
+                board.read_exact(&mut byte).unwrap();
+                command.extend_from_slice(&byte);
+            }
+            board.write_all(b"Preparing to receive...\r\nC").unwrap();
+
+            let mut packet = [0; 3 + 128 + 2];
+            board.read_exact(&mut packet).unwrap();
+            assert_eq!(packet[..8], [SOH, 1, 0xFE, b'h', b'e', b'l', b'l', b'o']);
+            board.write_all(&[ACK]).unwrap();
+
+            board.read_exact(&mut byte).unwrap();
+            assert_eq!(byte, [EOT]);
+            board.write_all(&reply_after_transfer("Receive successful.\r\n")).unwrap();
+
+            command
+        });
+
+        let mut port = port::open(&terminal.path, 115200).unwrap();
+        upload(&mut port, &path).unwrap();
+
+        assert_eq!(shell.join().unwrap(), b"rx hello.txt 5\r");
+    }
+
+    #[test]
+    fn quotes_file_names_the_shell_would_interpret() {
+        assert_eq!(quote("hello.txt"), "hello.txt");
+        assert_eq!(quote("two words"), "\"two words\"");
+        assert_eq!(quote("it's"), "\"it's\"");
+        assert_eq!(quote(r#"$HOME>\"x"#), r#""\$HOME>\\\"x""#);
+    }
+}
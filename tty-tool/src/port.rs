@@ -0,0 +1,186 @@
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    time::{Duration, Instant},
+};
+
+use serialport::SerialPort;
+
+/// What the transfer code needs from the connection to the board.
+pub(crate) trait Port: Read + Write {
+    /// Waits for a single byte, returning `None` on timeout.
+    fn read_byte(&mut self, timeout: Duration) -> io::Result<Option<u8>>;
+}
+
+impl Port for Box<dyn SerialPort> {
+    fn read_byte(&mut self, timeout: Duration) -> io::Result<Option<u8>> {
+        self.set_timeout(timeout)?;
+
+        let mut byte = [0];
+
+        match self.read(&mut byte) {
+            Ok(1) => Ok(Some(byte[0])),
+            Ok(_) => Ok(None),
+            Err(err) if err.kind() == ErrorKind::TimedOut => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Opens a serial port, or a pseudo-terminal standing in for the board.
+pub(crate) fn open(path: &str, baud_rate: u32) -> io::Result<Box<dyn SerialPort>> {
+    serialport::new(path, baud_rate)
+        .timeout(Duration::from_millis(100))
+        .open()
+        .map_err(io::Error::from)
+}
+
+/// Reads until `pattern` is seen and returns everything read up to and
+/// including it.
+pub(crate) fn read_until(
+    port: &mut impl Port,
+    pattern: &[u8],
+    timeout: Duration,
+) -> io::Result<Vec<u8>> {
+    let deadline = Instant::now() + timeout;
+    let mut received = Vec::new();
+
+    while !received.ends_with(pattern) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+
+        if remaining.is_zero() {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                format!(
+                    "expected {:?}, received {:?}",
+                    String::from_utf8_lossy(pattern),
+                    String::from_utf8_lossy(&received)
+                ),
+            ));
+        }
+
+        if let Some(byte) = port.read_byte(remaining)? {
+            received.push(byte);
+        }
+    }
+
+    Ok(received)
+}
+
+/// Stands in for the board in tests: starts out having sent `initial`, and
+/// answers each write with the next of `replies`.
+#[cfg(test)]
+pub(crate) struct ScriptedPort {
+    pub(crate) written: Vec<Vec<u8>>,
+    pending: std::collections::VecDeque<u8>,
+    replies: std::collections::VecDeque<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl ScriptedPort {
+    pub(crate) fn new(initial: &[u8], replies: &[&[u8]]) -> Self {
+        ScriptedPort {
+            written: Vec::new(),
+            pending: initial.iter().copied().collect(),
+            replies: replies.iter().map(|reply| reply.to_vec()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Read for ScriptedPort {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let length = buffer.len().min(self.pending.len());
+
+        for byte in buffer.iter_mut().take(length) {
+            *byte = self.pending.pop_front().unwrap();
+        }
+
+        Ok(length)
+    }
+}
+
+#[cfg(test)]
+impl Write for ScriptedPort {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.written.push(buffer.to_vec());
+        self.pending.extend(self.replies.pop_front().unwrap_or_default());
+
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Port for ScriptedPort {
+    // Nothing more will arrive once the pending bytes are read, so there's
+    // no point in waiting
+    fn read_byte(&mut self, _timeout: Duration) -> io::Result<Option<u8>> {
+        Ok(self.pending.pop_front())
+    }
+}
+
+/// A pseudo-terminal pair: the tool opens `path` as its port, and the test
+/// plays the board on the other end.
+#[cfg(test)]
+pub(crate) struct PseudoTerminal {
+    pub(crate) board: std::fs::File,
+    pub(crate) path: String,
+    // Closing every descriptor of the tool's end would hang up the board's
+    _tool_end: std::os::fd::OwnedFd,
+}
+
+#[cfg(test)]
+impl PseudoTerminal {
+    pub(crate) fn open() -> io::Result<Self> {
+        use std::os::fd::{AsRawFd, FromRawFd};
+
+        let mut board = -1;
+        let mut tool_end = -1;
+
+        let result = unsafe {
+            libc::openpty(
+                &mut board,
+                &mut tool_end,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+            )
+        };
+
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let board = unsafe { std::fs::File::from_raw_fd(board) };
+        let tool_end = unsafe { std::os::fd::OwnedFd::from_raw_fd(tool_end) };
+        let path = std::fs::read_link(format!("/proc/self/fd/{}", tool_end.as_raw_fd()))?;
+
+        Ok(PseudoTerminal {
+            board,
+            path: path.to_string_lossy().into_owned(),
+            _tool_end: tool_end,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_bytes_from_a_pseudo_terminal_until_the_timeout() {
+        let mut terminal = PseudoTerminal::open().unwrap();
+        let mut port = open(&terminal.path, 115200).unwrap();
+
+        terminal.board.write_all(b"ok").unwrap();
+
+        assert_eq!(read_until(&mut port, b"ok", Duration::from_secs(5)).unwrap(), b"ok");
+
+        let start = Instant::now();
+        assert_eq!(port.read_byte(Duration::from_millis(50)).unwrap(), None);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
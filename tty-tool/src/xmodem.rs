@@ -0,0 +1,195 @@
+use std::{io, time::Duration};
+
+use crate::port::Port;
+
+const SOH: u8 = 0x01; // Start of Header, 128-byte packet
+const STX: u8 = 0x02; // Start of Text, 1024-byte packet
+const EOT: u8 = 0x04; // End of Transmission
+const ACK: u8 = 0x06; // Acknowledge
+const NAK: u8 = 0x15; // Not Acknowledge
+const CAN: u8 = 0x18; // Cancel
+const SUB: u8 = 0x1A; // Substitute, pads the last packet
+const CRC: u8 = b'C'; // Requests CRC-16 instead of the 8-bit checksum
+
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+const PACKET_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MAX_ERRORS: u32 = 10;
+
+/// Sends `data` with XMODEM-1K if the board asks for CRC mode, or with the
+/// original 128-byte checksum packets otherwise.
+pub(crate) fn send(
+    port: &mut impl Port,
+    data: &[u8],
+    mut progress: impl FnMut(usize),
+) -> io::Result<()> {
+    let crc_mode = wait_for_receiver(port)?;
+    let packet_size = if crc_mode { 1024 } else { 128 };
+
+    let mut sent = 0;
+
+    for (index, chunk) in data.chunks(packet_size).enumerate() {
+        // Don't waste a 1K packet on a short tail
+        let size = if chunk.len() <= 128 { 128 } else { packet_size };
+
+        let mut packet = chunk.to_vec();
+        packet.resize(size, SUB);
+
+        send_packet(port, &packet, (index + 1) as u8, crc_mode)?;
+
+        sent += chunk.len();
+        progress(sent);
+    }
+
+    for _ in 0..MAX_ERRORS {
+        port.write_all(&[EOT])?;
+
+        if port.read_byte(PACKET_TIMEOUT)? == Some(ACK) {
+            return Ok(());
+        }
+    }
+
+    Err(error("end of transmission not acknowledged"))
+}
+
+fn wait_for_receiver(port: &mut impl Port) -> io::Result<bool> {
+    for _ in 0..MAX_ERRORS {
+        match port.read_byte(START_TIMEOUT)? {
+            Some(CRC) => return Ok(true),
+            Some(NAK) => return Ok(false),
+            Some(CAN) => return Err(error("transfer cancelled by the board")),
+            _ => (),
+        }
+    }
+
+    Err(error("the board did not start the transfer"))
+}
+
+fn send_packet(port: &mut impl Port, data: &[u8], block_id: u8, crc_mode: bool) -> io::Result<()> {
+    let start = if data.len() == 1024 { STX } else { SOH };
+
+    let mut packet = vec![start, block_id, !block_id];
+    packet.extend_from_slice(data);
+
+    if crc_mode {
+        let crc = data.iter().fold(0, |crc, &byte| crc16_update(crc, byte));
+        packet.extend_from_slice(&crc.to_be_bytes());
+    } else {
+        let check_sum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        packet.push(check_sum);
+    }
+
+    for _ in 0..MAX_ERRORS {
+        port.write_all(&packet)?;
+
+        match port.read_byte(PACKET_TIMEOUT)? {
+            Some(ACK) => return Ok(()),
+            Some(CAN) => return Err(error("transfer cancelled by the board")),
+            _ => (),
+        }
+    }
+
+    port.write_all(&[CAN, CAN, CAN])?;
+    Err(error("too many errors, transfer aborted"))
+}
+
+/// CRC-16/XMODEM: polynomial 0x1021, initial value 0, most significant bit first.
+fn crc16_update(crc: u16, byte: u8) -> u16 {
+    let mut crc = crc ^ (byte as u16) << 8;
+
+    for _ in 0..8 {
+        crc = if crc & 0x8000 != 0 {
+            crc << 1 ^ 0x1021
+        } else {
+            crc << 1
+        };
+    }
+
+    crc
+}
+
+fn error(message: &str) -> io::Error {
+    io::Error::other(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::port::ScriptedPort;
+
+    fn crc_packet(block_id: u8, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![SOH, block_id, !block_id];
+        packet.extend_from_slice(data);
+        packet.resize(3 + 128, SUB);
+
+        let crc = packet[3..].iter().fold(0, |crc, &byte| crc16_update(crc, byte));
+        packet.extend_from_slice(&crc.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn crc16_matches_the_xmodem_check_value() {
+        let crc = b"123456789".iter().fold(0, |crc, &byte| crc16_update(crc, byte));
+
+        assert_eq!(crc, 0x31C3);
+    }
+
+    #[test]
+    fn sends_packets_and_end_of_transmission_once_acknowledged() {
+        let mut port = ScriptedPort::new(&[CRC], &[&[ACK], &[ACK]]);
+
+        send(&mut port, b"hello", |_| ()).unwrap();
+
+        assert_eq!(port.written, [crc_packet(1, b"hello"), vec![EOT]]);
+    }
+
+    #[test]
+    fn sends_checksum_packets_when_the_board_asks_with_nak() {
+        let mut port = ScriptedPort::new(&[NAK], &[&[ACK], &[ACK]]);
+
+        send(&mut port, b"hi", |_| ()).unwrap();
+
+        let mut packet = vec![SOH, 1, 0xFE, b'h', b'i'];
+        packet.resize(3 + 128, SUB);
+        packet.push(packet[3..].iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)));
+
+        assert_eq!(port.written, [packet, vec![EOT]]);
+    }
+
+    #[test]
+    fn resends_a_packet_after_a_nak() {
+        let mut port = ScriptedPort::new(&[CRC], &[&[NAK], &[ACK], &[ACK]]);
+        let mut progress = Vec::new();
+
+        send(&mut port, &[0x55; 200], |sent| progress.push(sent)).unwrap();
+
+        // More than 128 bytes go into a 1K packet
+        assert_eq!(port.written.len(), 3);
+        assert_eq!(port.written[0][..3], [STX, 1, 0xFE]);
+        assert_eq!(port.written[0].len(), 3 + 1024 + 2);
+        assert_eq!(port.written[1], port.written[0]);
+        assert_eq!(port.written[2], [EOT]);
+        assert_eq!(progress, [200]);
+    }
+
+    #[test]
+    fn stops_when_the_board_cancels() {
+        let mut port = ScriptedPort::new(&[CRC], &[&[CAN]]);
+
+        let err = send(&mut port, b"hello", |_| ()).unwrap_err();
+
+        assert!(err.to_string().contains("cancelled"));
+        assert_eq!(port.written, [crc_packet(1, b"hello")]);
+    }
+
+    #[test]
+    fn gives_up_and_cancels_after_too_many_errors() {
+        let naks = [&[NAK][..]; MAX_ERRORS as usize];
+        let mut port = ScriptedPort::new(&[CRC], &naks);
+
+        send(&mut port, b"hello", |_| ()).unwrap_err();
+
+        assert_eq!(port.written.len(), MAX_ERRORS as usize + 1);
+        assert_eq!(port.written.last().unwrap(), &[CAN, CAN, CAN]);
+    }
+}
@@ -1,20 +1,66 @@
 #![no_std]
 #![no_main]
 
-use core::panic::PanicInfo;
+use core::{panic::PanicInfo, ptr::addr_of_mut};
 
 use syslib::put;
 
+// Checked at startup, to make sure that .rodata, .data and .bss were loaded
+// properly
+static mut LED_SEQUENCE: [u32; 2] = [0b010, 0b100];
+static mut RUN_COUNT: u32 = 0;
+static COLORS: [&str; 3] = ["red", "green", "blue"];
+
 #[panic_handler]
 fn panic_handler(_panic_info: &PanicInfo) -> ! {
     syscall::exit(1);
 }
 
+/// # Safety
+///
+/// Only called by the OS, with the arguments and variables from `run`.
 #[no_mangle]
-pub extern "C" fn _start() -> ! {
-    syscall::set_leds(0b010);
+pub unsafe extern "C" fn _start(argc: u32, argv: *const *const u8, envp: *const *const u8) -> ! {
+    if !statics_loaded() {
+        put!("Statics not loaded correctly.");
+        syscall::exit(2);
+    }
+
+    let led_sequence = addr_of_mut!(LED_SEQUENCE);
+
+    syscall::set_leds(unsafe { led_sequence.read_volatile()[0] });
     put!("Hello world.");
-    syscall::set_leds(0b100);
+
+    for arg in unsafe { syslib::args(argc, argv) }.skip(1) {
+        put!("Argument:", arg);
+    }
+
+    for (name, value) in unsafe { syslib::vars(envp) } {
+        put!("Variable:", name, value);
+    }
+
+    syscall::set_leds(unsafe { led_sequence.read_volatile()[1] });
 
     syscall::exit(0);
 }
+
+/// Reads the initial values of a .data, a .bss and a .rodata static, then
+/// checks that writes to them stick.
+fn statics_loaded() -> bool {
+    let led_sequence = addr_of_mut!(LED_SEQUENCE);
+    let run_count = addr_of_mut!(RUN_COUNT);
+
+    unsafe {
+        if led_sequence.read_volatile() != [0b010, 0b100] || run_count.read_volatile() != 0 {
+            return false;
+        }
+
+        run_count.write_volatile(run_count.read_volatile() + 1);
+        if run_count.read_volatile() != 1 {
+            return false;
+        }
+    }
+
+    let colors = core::hint::black_box(&COLORS);
+    colors[1] == "green" && colors[2].len() == 4
+}